@@ -0,0 +1,828 @@
+use std::str::FromStr;
+
+use rand::seq::SliceRandom;
+use kinode_process_lib::{
+    kiprintln, await_message,
+    println, call_init, timer,
+    Address, LazyLoadBlob, Message, Request, Response, SendError,
+};
+
+mod structs;
+use structs::*;
+
+wit_bindgen::generate!({
+    path: "target/wit",
+    world: "provider-template-dot-os-v0",
+    generate_unused_types: true,
+    additional_derives: [serde::Deserialize, serde::Serialize, process_macros::SerdeJsonInto],
+});
+
+/// How often every registered provider is pinged.
+const HEALTH_CHECK_INTERVAL_MS: u64 = 30_000;
+/// How long a provider has to answer a HealthPing.
+const HEALTH_PING_TIMEOUT_SECS: u64 = 10;
+/// Consecutive missed pings before a provider is kicked.
+const MAX_MISSED_PINGS: u32 = 3;
+/// How long a provider has to acknowledge an AssignWork.
+const ASSIGN_TIMEOUT_SECS: u64 = 10;
+/// How many times a job is handed out before it is reported as failed.
+const MAX_JOB_ATTEMPTS: u32 = 3;
+/// Most jobs grouped into one AssignBatch.
+const MAX_BATCH_SIZE: usize = 32;
+/// How many completed and failed jobs are kept around for GetResults.
+const MAX_KEPT_RESULTS: usize = 1000;
+/// How long a provider has to answer a registration challenge. Generous,
+/// since the UI may have to download the model first.
+const CHALLENGE_TTL_SECS: u64 = 600;
+
+fn peers_seed_path(our: &Address) -> String {
+    format!("/{}/pkg/coordinators.json", our.package_id())
+}
+
+fn load_peers(our: &Address) -> anyhow::Result<Vec<CoordinatorInfo>> {
+    let contents = kinode_process_lib::vfs::open_file(&peers_seed_path(our), false, Some(5))?
+        .read_to_string()?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+fn load_references(our: &Address) -> anyhow::Result<Vec<ReferenceEmbedding>> {
+    let path = format!("/{}/pkg/challenges.json", our.package_id());
+    let contents = kinode_process_lib::vfs::open_file(&path, false, Some(5))?
+        .read_to_string()?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// Add a reference to its model's set, replacing one for the same input.
+fn add_reference(state: &mut State, reference: ReferenceEmbedding) {
+    let references = state.references.entry(reference.model.clone()).or_default();
+    references.retain(|known| known.uri != reference.uri);
+    references.push(reference);
+}
+
+/// Check every claimed model's output against the reference it drew.
+fn verify_challenge(
+    challenge: &PendingChallenge,
+    outputs: &[ChallengeOutput],
+) -> Result<(), String> {
+    for model in &challenge.models {
+        let reference = challenge.references.iter()
+            .find(|reference| &reference.model == model)
+            .ok_or_else(|| format!("no challenge reference for {}", model))?;
+        let output = outputs.iter()
+            .find(|output| &output.model == model)
+            .ok_or_else(|| format!("no challenge output for {}", model))?;
+
+        if output.embeddings.len() != reference.embeddings.len() {
+            return Err(format!(
+                "{} produced {} dimensions, expected {}",
+                model, output.embeddings.len(), reference.embeddings.len()
+            ));
+        }
+        if output.embeddings.iter().any(|v| !v.is_finite()) {
+            return Err(format!("{} produced non-finite values", model));
+        }
+        let similarity = cosine_similarity(&output.embeddings, &reference.embeddings);
+        if similarity < 1.0 - reference.tolerance {
+            return Err(format!(
+                "{} output deviates from reference (cosine similarity {:.4})",
+                model, similarity
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Register `source` with what it offered in `challenge`.
+fn accept_provider(state: &mut State, source: &Address, challenge: PendingChallenge) -> anyhow::Result<()> {
+    // Re-registration replaces the old entry and frees its job
+    release_job(state, source, "provider re-registered");
+    state.providers.insert(source.clone(), ProviderEntry {
+        supported_models: challenge.models,
+        capabilities: challenge.capabilities,
+        models: challenge.specs,
+        protocol_version: challenge.protocol_version,
+        status: ProviderStatus::Idle,
+        current_job: None,
+        missed_pings: 0,
+        last_seen: now(),
+        last_report: None,
+    });
+    kiprintln!("registered provider {} (protocol v{})", source, challenge.protocol_version);
+    respond(&CoordinatorResponse::ProviderRegistered {
+        required_models: state.required_models.clone(),
+        protocol_version: challenge.protocol_version,
+        encoding: challenge.encoding,
+    })
+}
+
+fn respond<T: serde::Serialize>(response: &T) -> anyhow::Result<()> {
+    Response::new()
+        .body(serde_json::to_vec(response)?)
+        .send()
+}
+
+fn next_job_id(state: &mut State) -> String {
+    state.next_job_id += 1;
+    format!("{}-{}", now(), state.next_job_id)
+}
+
+fn record_failure(state: &mut State, job: Job, error: String) {
+    kiprintln!("job {} failed permanently: {}", job.request.id, error);
+    state.failed.push_back(WorkError {
+        id: job.request.id,
+        error,
+        kind: WorkErrorKind::Other,
+        timestamp: now(),
+    });
+    while state.failed.len() > MAX_KEPT_RESULTS {
+        state.failed.pop_front();
+    }
+}
+
+/// Put a job back at the front of the queue, or give up on it once it has
+/// been handed out MAX_JOB_ATTEMPTS times.
+fn requeue(state: &mut State, job: Job, reason: &str) {
+    if job.attempts >= MAX_JOB_ATTEMPTS {
+        record_failure(state, job, format!("gave up after {} attempts: {}", MAX_JOB_ATTEMPTS, reason));
+    } else {
+        kiprintln!("requeueing job {}: {}", job.request.id, reason);
+        state.queue.push_front(job);
+    }
+}
+
+/// Take the job currently held by `provider` out of flight and requeue it.
+fn release_job(state: &mut State, provider: &Address, reason: &str) {
+    let Some(entry) = state.providers.get_mut(provider) else {
+        return;
+    };
+    let Some(job_id) = entry.current_job.take() else {
+        return;
+    };
+    let job_ids = state.batches.remove(&job_id).unwrap_or_else(|| vec![job_id]);
+    for job_id in job_ids {
+        if let Some((_, job)) = state.in_flight.remove(&job_id) {
+            requeue(state, job, reason);
+        }
+    }
+}
+
+/// Take up to `limit` queued jobs matching `wanted` out of the queue, oldest first.
+fn take_queued(
+    state: &mut State,
+    limit: usize,
+    wanted: impl Fn(&WorkRequest) -> bool,
+) -> Vec<Job> {
+    let mut taken = Vec::new();
+    let mut rest = std::collections::VecDeque::new();
+    for job in state.queue.drain(..) {
+        if taken.len() < limit && wanted(&job.request) {
+            taken.push(job);
+        } else {
+            rest.push_back(job);
+        }
+    }
+    state.queue = rest;
+    taken
+}
+
+fn kick_provider(state: &mut State, provider: &Address, reason: &str) -> anyhow::Result<()> {
+    kiprintln!("kicking provider {}: {}", provider, reason);
+    release_job(state, provider, reason);
+    state.providers.remove(provider);
+    Request::to(provider)
+        .body(serde_json::to_vec(&ProviderRequest::Kick)?)
+        .send()
+}
+
+/// Hand queued jobs to idle providers that support the job's model.
+fn dispatch(state: &mut State) -> anyhow::Result<()> {
+    let mut skipped = Vec::new();
+
+    while let Some(job) = state.queue.pop_front() {
+        let provider = state.providers.iter()
+            .filter(|(_, entry)| {
+                entry.status == ProviderStatus::Idle && entry.accepts(&job.request)
+            })
+            .min_by_key(|(_, entry)| entry.dispatch_rank(&job.request.model))
+            .map(|(address, _)| address.clone());
+
+        let Some(provider) = provider else {
+            skipped.push(job);
+            continue;
+        };
+
+        // Providers that understand batches get every queued job for the
+        // same model in one assignment
+        let mut jobs = vec![job];
+        if let Some(entry) = state.providers.get(&provider)
+            .filter(|entry| entry.protocol_version >= protocol::MIN_BATCH_VERSION)
+            .cloned()
+        {
+            let model = jobs[0].request.model.clone();
+            jobs.extend(take_queued(state, MAX_BATCH_SIZE - 1, |request| {
+                request.model == model && entry.accepts(request)
+            }));
+        }
+        for job in &mut jobs {
+            job.attempts += 1;
+        }
+
+        let (request, assignment_id) = if jobs.len() == 1 {
+            let request = &jobs[0].request;
+            kiprintln!("assigning job {} to {}", request.id, provider);
+            (ProviderRequest::AssignWork(request.clone()), request.id.clone())
+        } else {
+            let batch = WorkBatch {
+                id: next_job_id(state),
+                model: jobs[0].request.model.clone(),
+                items: jobs.iter()
+                    .map(|job| BatchItem {
+                        id: job.request.id.clone(),
+                        uri: job.request.uri.clone(),
+                        input: job.request.input.clone(),
+                    })
+                    .collect(),
+                timestamp: now(),
+            };
+            kiprintln!("assigning batch {} of {} jobs to {}", batch.id, jobs.len(), provider);
+            state.batches.insert(
+                batch.id.clone(),
+                jobs.iter().map(|job| job.request.id.clone()).collect(),
+            );
+            let id = batch.id.clone();
+            (ProviderRequest::AssignBatch(batch), id)
+        };
+
+        Request::to(&provider)
+            .body(serde_json::to_vec(&request)?)
+            .context(serde_json::to_vec(&PendingContext::AssignWork {
+                provider: provider.clone(),
+                job_id: assignment_id.clone(),
+            })?)
+            .expects_response(ASSIGN_TIMEOUT_SECS)
+            .send()?;
+
+        if let Some(entry) = state.providers.get_mut(&provider) {
+            entry.status = ProviderStatus::Working;
+            entry.current_job = Some(assignment_id);
+        }
+        for job in jobs {
+            state.in_flight.insert(job.request.id.clone(), (provider.clone(), job));
+        }
+    }
+
+    state.queue.extend(skipped);
+    Ok(())
+}
+
+fn health_check(state: &mut State) -> anyhow::Result<()> {
+    let providers: Vec<Address> = state.providers.iter()
+        .filter(|(_, entry)| entry.status != ProviderStatus::Offline)
+        .map(|(address, _)| address.clone())
+        .collect();
+
+    for provider in providers {
+        Request::to(&provider)
+            .body(serde_json::to_vec(&ProviderRequest::HealthPing)?)
+            .context(serde_json::to_vec(&PendingContext::HealthPing(provider.clone()))?)
+            .expects_response(HEALTH_PING_TIMEOUT_SECS)
+            .send()?;
+    }
+
+    timer::set_timer(
+        HEALTH_CHECK_INTERVAL_MS,
+        Some(serde_json::to_vec(&PendingContext::HealthCheck)?),
+    );
+    Ok(())
+}
+
+fn missed_ping(state: &mut State, provider: &Address, reason: &str) -> anyhow::Result<()> {
+    let Some(entry) = state.providers.get_mut(provider) else {
+        return Ok(());
+    };
+    entry.missed_pings += 1;
+    kiprintln!("provider {} missed a ping ({}/{}): {}", provider, entry.missed_pings, MAX_MISSED_PINGS, reason);
+    if entry.missed_pings >= MAX_MISSED_PINGS {
+        kick_provider(state, provider, "stopped responding")?;
+    }
+    Ok(())
+}
+
+fn handle_coordinator_request(
+    our: &Address,
+    state: &mut State,
+    source: &Address,
+    request: CoordinatorRequest,
+) -> anyhow::Result<()> {
+    match request {
+        CoordinatorRequest::RegisterProvider {
+            supported_models,
+            protocol_version,
+            capabilities,
+            models: specs,
+            encodings,
+        } => {
+            let protocol_version = match protocol::negotiate_version(protocol_version) {
+                Ok(version) => version,
+                Err(reason) => {
+                    kiprintln!("rejecting provider {}: {}", source, reason);
+                    return respond(&CoordinatorResponse::RegistrationRejected { reason });
+                }
+            };
+            if protocol_version < protocol::MIN_HANDSHAKE_VERSION {
+                return respond(&CoordinatorResponse::RegistrationRejected {
+                    reason: format!(
+                        "registration requires protocol v{} or newer",
+                        protocol::MIN_HANDSHAKE_VERSION
+                    ),
+                });
+            }
+
+            let missing: Vec<&String> = state.required_models.iter()
+                .filter(|model| !supported_models.contains(model))
+                .collect();
+
+            if !missing.is_empty() {
+                kiprintln!("rejecting provider {}: missing models {:?}", source, missing);
+                return respond(&CoordinatorResponse::RegistrationRejected {
+                    reason: format!("missing required models: {:?}", missing),
+                });
+            }
+
+            // A declared dimension that cannot match the reference would
+            // only fail the challenge later
+            if let Some((spec, reference)) = specs.iter()
+                .filter_map(|spec| state.references.get(&spec.id)?.first().map(|r| (spec, r)))
+                .find(|(spec, reference)| spec.dimension as usize != reference.embeddings.len())
+            {
+                return respond(&CoordinatorResponse::RegistrationRejected {
+                    reason: format!(
+                        "{} declares {} dimensions, expected {}",
+                        spec.id, spec.dimension, reference.embeddings.len()
+                    ),
+                });
+            }
+
+            // Every claimed model is checked, one we cannot check is refused
+            let unverifiable: Vec<&String> = supported_models.iter()
+                .filter(|model| !state.references.contains_key(*model))
+                .collect();
+            if !unverifiable.is_empty() {
+                kiprintln!("rejecting provider {}: no challenge references for {:?}", source, unverifiable);
+                return respond(&CoordinatorResponse::RegistrationRejected {
+                    reason: format!("no challenge references for {:?}", unverifiable),
+                });
+            }
+
+            // A fresh draw per challenge, so a recorded answer cannot be
+            // replayed on the next one
+            let models = supported_models;
+            let mut rng = rand::thread_rng();
+            let references: Vec<ReferenceEmbedding> = models.iter()
+                .filter_map(|model| state.references.get(model)?.choose(&mut rng).cloned())
+                .collect();
+            let capabilities = capabilities.into_iter()
+                .filter(|c| models.contains(&c.model))
+                .collect();
+            let specs = specs.into_iter()
+                .filter(|spec| models.contains(&spec.id))
+                .collect();
+            let challenge = PendingChallenge {
+                challenge_id: next_job_id(state),
+                models,
+                references,
+                capabilities,
+                specs,
+                encoding: protocol::choose_encoding(&encodings),
+                protocol_version,
+                issued_at: now(),
+            };
+            let inputs = challenge.references.iter()
+                .map(|reference| ChallengeInput {
+                    model: reference.model.clone(),
+                    uri: reference.uri.clone(),
+                })
+                .collect();
+            let challenge_id = challenge.challenge_id.clone();
+            state.challenges.insert(source.clone(), challenge);
+            kiprintln!("challenging provider {} with {}", source, challenge_id);
+            respond(&CoordinatorResponse::Challenge { challenge_id, inputs })?;
+        }
+        CoordinatorRequest::ChallengeResponse { challenge_id, outputs } => {
+            let Some(challenge) = state.challenges.remove(source) else {
+                return respond(&CoordinatorResponse::RegistrationRejected {
+                    reason: "no pending challenge".to_string(),
+                });
+            };
+            if challenge.challenge_id != challenge_id {
+                return respond(&CoordinatorResponse::RegistrationRejected {
+                    reason: "challenge id mismatch".to_string(),
+                });
+            }
+            if now().saturating_sub(challenge.issued_at) > CHALLENGE_TTL_SECS {
+                return respond(&CoordinatorResponse::RegistrationRejected {
+                    reason: "challenge expired".to_string(),
+                });
+            }
+            if let Err(reason) = verify_challenge(&challenge, &outputs) {
+                kiprintln!("provider {} failed challenge: {}", source, reason);
+                return respond(&CoordinatorResponse::RegistrationRejected { reason });
+            }
+            accept_provider(state, source, challenge)?;
+        }
+        CoordinatorRequest::ProviderReady => {
+            let Some(entry) = state.providers.get_mut(source) else {
+                return respond(&CoordinatorResponse::Nack);
+            };
+            entry.last_seen = now();
+            entry.missed_pings = 0;
+            if entry.status == ProviderStatus::Offline {
+                entry.status = ProviderStatus::Idle;
+            }
+            respond(&CoordinatorResponse::Ack)?;
+        }
+        CoordinatorRequest::GoOffline => {
+            release_job(state, source, "provider went offline");
+            match state.providers.get_mut(source) {
+                Some(entry) => {
+                    entry.status = ProviderStatus::Offline;
+                    respond(&CoordinatorResponse::Ack)?;
+                }
+                None => respond(&CoordinatorResponse::Nack)?,
+            }
+        }
+        CoordinatorRequest::GetPeers => {
+            let mut peers = state.peers.clone();
+            peers.push(CoordinatorInfo {
+                address: our.to_string(),
+                required_models: state.required_models.clone(),
+            });
+            respond(&CoordinatorResponse::Peers(peers))?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_provider_report(
+    state: &mut State,
+    source: &Address,
+    report: ProviderResponse,
+    blob: Option<LazyLoadBlob>,
+) -> anyhow::Result<()> {
+    let report_id = match &report {
+        ProviderResponse::WorkCompleted { result, .. } => result.id.clone(),
+        ProviderResponse::WorkFailed { error } => error.id.clone(),
+        ProviderResponse::BatchCompleted { result } => result.batch_id.clone(),
+        other => return Err(anyhow::anyhow!("unexpected report from {}: {:?}", source, other)),
+    };
+    let key = (report_id, source.clone());
+    if state.acknowledged.contains(&key) && !holds(state, source, &key.0) {
+        kiprintln!("{} resent its report on {}, already processed", source, key.0);
+        return Ok(());
+    }
+
+    let report = match report {
+        ProviderResponse::BatchCompleted { result } => {
+            handle_batch_report(state, source, result)?;
+            acknowledge(state, key);
+            return Ok(());
+        }
+        report => report,
+    };
+    let job_id = key.0.clone();
+
+    // Only the provider the job was assigned to may report on it
+    match state.in_flight.get(&job_id) {
+        Some((assignee, _)) if assignee == source => {}
+        _ => return Err(anyhow::anyhow!("{} reported on job {} it does not hold", source, job_id)),
+    }
+    let Some((_, job)) = state.in_flight.remove(&job_id) else {
+        return Ok(());
+    };
+
+    if let Some(entry) = state.providers.get_mut(source) {
+        entry.status = ProviderStatus::Idle;
+        entry.current_job = None;
+        entry.last_seen = now();
+    }
+
+    match report {
+        ProviderResponse::WorkCompleted { mut result, encoded } => {
+            if let Some(encoded) = encoded {
+                let bytes = blob.map(|blob| blob.bytes).unwrap_or_default();
+                match encoded.decode(&bytes) {
+                    Ok(embeddings) => result.embeddings = embeddings,
+                    Err(reason) => {
+                        requeue(state, job, &format!("undecodable result: {}", reason));
+                        acknowledge(state, key);
+                        return dispatch(state);
+                    }
+                }
+            }
+            // Providers below MIN_MODALITY_VERSION do not tag their results
+            result.space.get_or_insert_with(|| EmbeddingSpace {
+                model: job.request.model.clone(),
+                modality: job.request.input().modality(),
+            });
+            kiprintln!("job {} completed by {} ({} dims)", job_id, source, result.embeddings.len());
+            state.completed.push_back(result);
+            while state.completed.len() > MAX_KEPT_RESULTS {
+                state.completed.pop_front();
+            }
+        }
+        ProviderResponse::WorkFailed { error } => {
+            requeue(state, job, &error.error);
+        }
+        _ => {}
+    }
+
+    acknowledge(state, key);
+    dispatch(state)
+}
+
+/// Whether `provider` holds the job or batch `id` right now.
+fn holds(state: &State, provider: &Address, id: &str) -> bool {
+    let job_id = state.batches.get(id)
+        .and_then(|job_ids| job_ids.first())
+        .map(String::as_str)
+        .unwrap_or(id);
+    state.in_flight.get(job_id).is_some_and(|(assignee, _)| assignee == provider)
+}
+
+/// Remember a processed report so a resent copy is not applied again.
+fn acknowledge(state: &mut State, key: (String, Address)) {
+    state.acknowledged.push_back(key);
+    while state.acknowledged.len() > MAX_KEPT_RESULTS {
+        state.acknowledged.pop_front();
+    }
+}
+
+fn handle_batch_report(
+    state: &mut State,
+    source: &Address,
+    result: BatchResult,
+) -> anyhow::Result<()> {
+    let held = state.batches.get(&result.batch_id)
+        .and_then(|job_ids| job_ids.first())
+        .and_then(|job_id| state.in_flight.get(job_id))
+        .is_some_and(|(assignee, _)| assignee == source);
+    if !held {
+        return Err(anyhow::anyhow!("{} reported on batch {} it does not hold", source, result.batch_id));
+    }
+    let job_ids = state.batches.remove(&result.batch_id).unwrap_or_default();
+
+    if let Some(entry) = state.providers.get_mut(source) {
+        if entry.current_job.as_ref() == Some(&result.batch_id) {
+            entry.status = ProviderStatus::Idle;
+            entry.current_job = None;
+        }
+        entry.last_seen = now();
+    }
+
+    let mut completed = 0;
+    for job_id in job_ids {
+        let Some((_, job)) = state.in_flight.remove(&job_id) else {
+            continue;
+        };
+        match result.results.iter().find(|item| item.id == job_id) {
+            Some(ItemResult { outcome: ItemOutcome::Completed { embeddings }, .. }) => {
+                completed += 1;
+                state.completed.push_back(WorkResult {
+                    id: job_id,
+                    embeddings: embeddings.clone(),
+                    space: Some(EmbeddingSpace {
+                        model: job.request.model.clone(),
+                        modality: job.request.input().modality(),
+                    }),
+                    timestamp: result.timestamp,
+                });
+            }
+            Some(ItemResult { outcome: ItemOutcome::Failed { error }, .. }) => {
+                requeue(state, job, error);
+            }
+            None => requeue(state, job, "missing from batch result"),
+        }
+    }
+    while state.completed.len() > MAX_KEPT_RESULTS {
+        state.completed.pop_front();
+    }
+    kiprintln!("batch {} from {}: {}/{} items completed", result.batch_id, source, completed, result.results.len());
+
+    dispatch(state)
+}
+
+fn handle_admin_request(state: &mut State, request: AdminRequest) -> anyhow::Result<()> {
+    match request {
+        AdminRequest::SubmitWork { model, input, timeout_ms } => {
+            if !state.required_models.contains(&model) {
+                return respond(&AdminResponse::Error(format!("unsupported model {}", model)));
+            }
+            let id = next_job_id(state);
+            state.queue.push_back(Job {
+                request: WorkRequest {
+                    id: id.clone(),
+                    model,
+                    // Older providers only read the uri
+                    uri: match &input {
+                        WorkInput::ImageUri { uri } => uri.clone(),
+                        _ => String::new(),
+                    },
+                    input: Some(input),
+                    timeout_ms,
+                    timestamp: now(),
+                },
+                attempts: 0,
+            });
+            respond(&AdminResponse::WorkSubmitted { id })?;
+            dispatch(state)?;
+        }
+        AdminRequest::GetResults => {
+            respond(&AdminResponse::Results {
+                completed: state.completed.iter().cloned().collect(),
+                failed: state.failed.iter().cloned().collect(),
+            })?;
+        }
+        AdminRequest::SetReference(reference) => {
+            let model = reference.model.clone();
+            add_reference(state, reference);
+            respond(&AdminResponse::ReferenceSet { model })?;
+        }
+        AdminRequest::GetProviders => {
+            respond(&AdminResponse::Providers(
+                state.providers.iter()
+                    .map(|(address, entry)| (address.clone(), entry.clone()))
+                    .collect(),
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_request(our: &Address, state: &mut State, message: &Message) -> anyhow::Result<()> {
+    match serde_json::from_slice(message.body())? {
+        IncomingRequest::Coordinator(request) => {
+            handle_coordinator_request(our, state, message.source(), request)?;
+            dispatch(state)?;
+        }
+        IncomingRequest::Provider(report) => {
+            let result = handle_provider_report(state, message.source(), report, message.blob());
+            // Providers from MIN_REPORT_ACK_VERSION on wait for an answer and
+            // resend the report until they get one
+            if matches!(message, Message::Request { expects_response: Some(_), .. }) {
+                let response = match result {
+                    Ok(()) => CoordinatorResponse::Ack,
+                    Err(_) => CoordinatorResponse::Nack,
+                };
+                Response::new()
+                    .body(serde_json::to_vec(&response)?)
+                    .send()?;
+            }
+            result?;
+        }
+        IncomingRequest::Admin(request) => {
+            if message.source().node() != our.node() {
+                return Err(anyhow::anyhow!("admin request from remote node {}", message.source()));
+            }
+            handle_admin_request(state, request)?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_response(state: &mut State, message: &Message) -> anyhow::Result<()> {
+    let Some(context) = message.context() else {
+        return Err(anyhow::anyhow!("response without context from {}", message.source()));
+    };
+
+    match serde_json::from_slice(context)? {
+        PendingContext::HealthCheck => health_check(state)?,
+        PendingContext::HealthPing(provider) => {
+            match serde_json::from_slice(message.body()) {
+                Ok(ProviderResponse::HealthPong) => {
+                    if let Some(entry) = state.providers.get_mut(&provider) {
+                        entry.missed_pings = 0;
+                        entry.last_seen = now();
+                    }
+                }
+                Ok(ProviderResponse::HealthReport(report)) => {
+                    if let Some(entry) = state.providers.get_mut(&provider) {
+                        entry.missed_pings = 0;
+                        entry.last_seen = now();
+                        if entry.status == ProviderStatus::Working
+                            && entry.current_job.is_none()
+                            && report.state == ProviderState::Idle
+                        {
+                            entry.status = ProviderStatus::Idle;
+                        }
+                        entry.last_report = Some(report);
+                    }
+                    dispatch(state)?;
+                }
+                _ => missed_ping(state, &provider, "unexpected HealthPing response")?,
+            }
+        }
+        PendingContext::AssignWork { provider, job_id } => {
+            match serde_json::from_slice(message.body()) {
+                Ok(ProviderResponse::WorkAssigned) => {
+                    kiprintln!("{} accepted job {}", provider, job_id);
+                }
+                Ok(ProviderResponse::QueueFull { max_depth }) => {
+                    // Still busy with work from elsewhere; its next health
+                    // report showing Idle makes it eligible again
+                    kiprintln!("{} has a full queue ({}), job {} goes back", provider, max_depth, job_id);
+                    release_job(state, &provider, "provider queue full");
+                    dispatch(state)?;
+                }
+                other => {
+                    kiprintln!("{} refused job {}: {:?}", provider, job_id, other.ok());
+                    release_job(state, &provider, "assignment refused");
+                    if let Some(entry) = state.providers.get_mut(&provider) {
+                        entry.status = ProviderStatus::Offline;
+                    }
+                    dispatch(state)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_send_error(state: &mut State, error: &SendError) -> anyhow::Result<()> {
+    let Some(context) = error.context() else {
+        return Ok(());
+    };
+
+    match serde_json::from_slice(context)? {
+        PendingContext::HealthCheck => {}
+        PendingContext::HealthPing(provider) => {
+            missed_ping(state, &provider, &format!("{:?}", error.kind()))?;
+        }
+        PendingContext::AssignWork { provider, job_id } => {
+            kiprintln!("assignment of job {} to {} failed: {:?}", job_id, provider, error.kind());
+            release_job(state, &provider, "provider unreachable");
+            missed_ping(state, &provider, "assignment not acknowledged")?;
+            if let Some(entry) = state.providers.get_mut(&provider) {
+                entry.status = ProviderStatus::Offline;
+            }
+            dispatch(state)?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_message(our: &Address, state: &mut State) -> anyhow::Result<()> {
+    match await_message() {
+        Err(send_error) => handle_send_error(state, &send_error)?,
+        Ok(message) if message.is_request() => handle_request(our, state, &message)?,
+        Ok(message) => handle_response(state, &message)?,
+    }
+    save_state(state)
+}
+
+call_init!(init);
+fn init(our: Address) -> anyhow::Result<()> {
+    println!("coordinator: begin");
+
+    let mut state = load_state();
+
+    match load_peers(&our) {
+        Ok(peers) => {
+            state.peers = peers.into_iter()
+                .filter(|peer| Address::from_str(&peer.address).is_ok_and(|a| a != our))
+                .collect();
+        }
+        Err(e) => kiprintln!("failed to load peer coordinators: {e}"),
+    }
+
+    match load_references(&our) {
+        Ok(references) => {
+            for reference in references {
+                add_reference(&mut state, reference);
+            }
+        }
+        Err(e) => kiprintln!("failed to load challenge references: {e}"),
+    }
+    for model in &state.required_models {
+        if !state.references.contains_key(model) {
+            kiprintln!("no challenge references for {}, providers cannot register until pkg/challenges.json has one", model);
+        }
+    }
+
+    health_check(&mut state).expect("failed to start health checks");
+
+    loop {
+        if let Err(e) = handle_message(&our, &mut state) {
+            kiprintln!("Error handling message: {e}");
+        }
+    }
+}
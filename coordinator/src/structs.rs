@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use kinode_process_lib::{kiprintln, Address};
+pub use protocol::{
+    BatchItem, BatchResult, ItemOutcome, ItemResult,
+    ChallengeInput, ChallengeOutput,
+    CoordinatorInfo, CoordinatorRequest, CoordinatorResponse,
+    EmbeddingEncoding, EmbeddingSpace, HealthReport, Modality, ModelCapabilities, ModelSpec,
+    ProviderRequest, ProviderResponse, ProviderState,
+    WorkBatch, WorkError, WorkErrorKind, WorkInput, WorkRequest, WorkResult,
+};
+
+// Local requests used to drive the coordinator during development
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AdminRequest {
+    SubmitWork {
+        model: String,
+        input: WorkInput,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+    GetResults,
+    GetProviders,
+    SetReference(ReferenceEmbedding),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AdminResponse {
+    WorkSubmitted { id: String },
+    Results { completed: Vec<WorkResult>, failed: Vec<WorkError> },
+    Providers(Vec<(Address, ProviderEntry)>),
+    ReferenceSet { model: String },
+    Error(String),
+}
+
+// Anything a coordinator can receive as a request
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum IncomingRequest {
+    Coordinator(CoordinatorRequest),
+    Provider(ProviderResponse),
+    Admin(AdminRequest),
+}
+
+// Carried in the context of outgoing requests so responses and
+// send errors can be matched back to what triggered them
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PendingContext {
+    HealthCheck,
+    HealthPing(Address),
+    AssignWork { provider: Address, job_id: String },
+}
+
+// Coordinator-side view of a provider
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ProviderStatus {
+    Idle,
+    Working,
+    Offline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderEntry {
+    pub supported_models: Vec<String>,
+    pub capabilities: Vec<ModelCapabilities>,
+    pub models: Vec<ModelSpec>,
+    pub protocol_version: u32,
+    pub status: ProviderStatus,
+    // Job id, or batch id when the provider was handed a batch
+    pub current_job: Option<String>,
+    pub missed_pings: u32,
+    pub last_seen: u64,
+    pub last_report: Option<HealthReport>,
+}
+
+impl ProviderEntry {
+    /// Whether the provider can run `request`. Models without announced
+    /// capabilities take images only.
+    pub fn accepts(&self, request: &WorkRequest) -> bool {
+        if !self.supported_models.contains(&request.model) {
+            return false;
+        }
+        let input = request.input();
+        if !input.is_legacy() && self.protocol_version < protocol::MIN_MODALITY_VERSION {
+            return false;
+        }
+        if let Some(spec) = self.models.iter().find(|m| m.id == request.model) {
+            return spec.modalities.contains(&input.modality());
+        }
+        match self.capabilities.iter().find(|c| c.model == request.model) {
+            Some(capabilities) => capabilities.supports(input.modality()),
+            None => input.modality() == Modality::Image,
+        }
+    }
+
+    /// Ordering key for dispatch, lower is better: providers with the model
+    /// already warm first, then by average latency.
+    pub fn dispatch_rank(&self, model: &str) -> (bool, u64) {
+        let Some(report) = &self.last_report else {
+            return (true, u64::MAX);
+        };
+        let warm = report.models.iter().any(|m| m.model == model && m.warm);
+        (!warm, report.avg_latency_ms.unwrap_or(u64::MAX))
+    }
+}
+
+// Known-good embedding of `uri` that challenge answers are compared against.
+// An answer passes if its cosine similarity is at least 1 - tolerance. A
+// model may have several, each challenge draws one of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceEmbedding {
+    pub model: String,
+    pub uri: String,
+    pub embeddings: Vec<f32>,
+    pub tolerance: f32,
+}
+
+// Challenge sent to a provider that has not answered yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingChallenge {
+    pub challenge_id: String,
+    pub models: Vec<String>,
+    // The reference drawn for each claimed model. A model without one is
+    // refused at registration, so there is one per entry of `models`
+    pub references: Vec<ReferenceEmbedding>,
+    pub capabilities: Vec<ModelCapabilities>,
+    pub specs: Vec<ModelSpec>,
+    pub encoding: Option<EmbeddingEncoding>,
+    pub protocol_version: u32,
+    pub issued_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub request: WorkRequest,
+    pub attempts: u32,
+}
+
+// Persisted through STATE_ENVELOPE. New fields need #[serde(default)] or a
+// migration there
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct State {
+    pub required_models: Vec<String>,
+    pub providers: HashMap<Address, ProviderEntry>,
+    pub queue: VecDeque<Job>,
+    pub in_flight: HashMap<String, (Address, Job)>,
+    // Batch id to the ids of the in-flight jobs it carries
+    pub batches: HashMap<String, Vec<String>>,
+    pub completed: VecDeque<WorkResult>,
+    pub failed: VecDeque<WorkError>,
+    // Reports already processed, by job or batch id and reporting provider,
+    // so a resent report is acknowledged without being applied twice
+    pub acknowledged: VecDeque<(String, Address)>,
+    pub peers: Vec<CoordinatorInfo>,
+    pub references: HashMap<String, Vec<ReferenceEmbedding>>,
+    pub challenges: HashMap<Address, PendingChallenge>,
+    pub next_job_id: u64,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self {
+            required_models: vec!["clip-vit-base-patch16".to_string()],
+            providers: HashMap::new(),
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+            batches: HashMap::new(),
+            completed: VecDeque::new(),
+            failed: VecDeque::new(),
+            acknowledged: VecDeque::new(),
+            peers: Vec::new(),
+            references: HashMap::new(),
+            challenges: HashMap::new(),
+            next_job_id: 0,
+        }
+    }
+}
+
+/// Version written by this build. Bump it together with a migration
+/// whenever a change to `State` is not covered by `#[serde(default)]`.
+const STATE_ENVELOPE: protocol::StateEnvelope = protocol::StateEnvelope {
+    magic: b"HCST",
+    version: 1,
+    migrations: &[],
+};
+
+pub fn save_state(state: &State) -> anyhow::Result<()> {
+    kinode_process_lib::set_state(&STATE_ENVELOPE.encode(state).map_err(anyhow::Error::msg)?);
+    Ok(())
+}
+
+/// The saved state, or a fresh one if there is none or it cannot be read.
+pub fn load_state() -> State {
+    let Some(bytes) = kinode_process_lib::get_state() else {
+        return State::new();
+    };
+    STATE_ENVELOPE.decode(&bytes).unwrap_or_else(|e| {
+        kiprintln!("saved state is unreadable, starting fresh: {e}");
+        State::new()
+    })
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
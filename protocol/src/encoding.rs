@@ -0,0 +1,143 @@
+//! Binary embedding encodings.
+//!
+//! A message carrying binary embeddings describes them with an
+//! `EncodedEmbeddings` header and moves the bytes into its blob, so large
+//! vectors never pass through the JSON body.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum EmbeddingEncoding {
+    // 4 bytes per value, lossless
+    F32Le,
+    // 2 bytes per value, IEEE 754 half precision
+    F16Le,
+    // 1 byte per value, multiplied by `EncodedEmbeddings::scale` on decode
+    Int8,
+}
+
+/// Encodings this build can decode, in the order it prefers them.
+pub const SUPPORTED_ENCODINGS: [EmbeddingEncoding; 3] = [
+    EmbeddingEncoding::F32Le,
+    EmbeddingEncoding::F16Le,
+    EmbeddingEncoding::Int8,
+];
+
+/// Pick the first encoding in `offered` that this build supports.
+pub fn choose_encoding(offered: &[EmbeddingEncoding]) -> Option<EmbeddingEncoding> {
+    offered.iter().copied().find(|e| SUPPORTED_ENCODINGS.contains(e))
+}
+
+// Describes embedding bytes carried in a blob
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EncodedEmbeddings {
+    pub encoding: EmbeddingEncoding,
+    pub dimension: u32,
+    // Only used by Int8
+    #[serde(default)]
+    pub scale: f32,
+}
+
+impl EmbeddingEncoding {
+    pub fn bytes_per_value(self) -> usize {
+        match self {
+            EmbeddingEncoding::F32Le => 4,
+            EmbeddingEncoding::F16Le => 2,
+            EmbeddingEncoding::Int8 => 1,
+        }
+    }
+
+    pub fn encode(self, values: &[f32]) -> (EncodedEmbeddings, Vec<u8>) {
+        let mut scale = 0.0;
+        let bytes = match self {
+            EmbeddingEncoding::F32Le => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            EmbeddingEncoding::F16Le => values.iter()
+                .flat_map(|v| f32_to_f16(*v).to_le_bytes())
+                .collect(),
+            EmbeddingEncoding::Int8 => {
+                let max = values.iter().fold(0.0f32, |max, v| max.max(v.abs()));
+                scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+                values.iter()
+                    .map(|v| (v / scale).round().clamp(-127.0, 127.0) as i8 as u8)
+                    .collect()
+            }
+        };
+        let header = EncodedEmbeddings {
+            encoding: self,
+            dimension: values.len() as u32,
+            scale,
+        };
+        (header, bytes)
+    }
+}
+
+impl EncodedEmbeddings {
+    pub fn decode(&self, bytes: &[u8]) -> Result<Vec<f32>, String> {
+        let expected = self.dimension as usize * self.encoding.bytes_per_value();
+        if bytes.len() != expected {
+            return Err(format!(
+                "{:?} payload of {} dimensions should be {} bytes, got {}",
+                self.encoding, self.dimension, expected, bytes.len()
+            ));
+        }
+        Ok(match self.encoding {
+            EmbeddingEncoding::F32Le => bytes.chunks_exact(4)
+                .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect(),
+            EmbeddingEncoding::F16Le => bytes.chunks_exact(2)
+                .map(|c| f16_to_f32(u16::from_le_bytes([c[0], c[1]])))
+                .collect(),
+            EmbeddingEncoding::Int8 => bytes.iter()
+                .map(|b| *b as i8 as f32 * self.scale)
+                .collect(),
+        })
+    }
+}
+
+/// Round to the nearest half precision value. Out of range values become
+/// infinity, values too small for a subnormal become zero.
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let round = (mantissa >> (shift - 1)) & 1;
+        return sign | ((mantissa >> shift) + round) as u16;
+    }
+    // A carry out of the mantissa correctly bumps the exponent
+    let round = (mantissa >> 12) & 1;
+    sign | ((((exponent as u32) << 10) | (mantissa >> 13)) + round) as u16
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let negative = half & 0x8000 != 0;
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+
+    match (exponent, mantissa) {
+        (0, 0) => f32::from_bits(sign),
+        (0, _) => {
+            let value = mantissa as f32 * 2f32.powi(-24);
+            if negative { -value } else { value }
+        }
+        (0x1f, 0) => f32::from_bits(sign | 0x7f80_0000),
+        (0x1f, _) => f32::NAN,
+        _ => f32::from_bits(sign | ((exponent + 112) << 23) | (mantissa << 13)),
+    }
+}
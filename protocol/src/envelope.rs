@@ -0,0 +1,52 @@
+//! Versioned envelope around a process's persisted state.
+//!
+//! The magic, the version as a little-endian u32, then the state as JSON, so
+//! fields added with `#[serde(default)]` need no migration. Every other
+//! change to a persisted type bumps the version and adds a migration.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Upgrades a state one version up.
+pub type Migration = fn(serde_json::Value) -> Result<serde_json::Value, String>;
+
+pub struct StateEnvelope {
+    // Tells this process's states apart from anything else
+    pub magic: &'static [u8; 4],
+    // Version written by this build
+    pub version: u32,
+    // `migrations[0]` takes v1 to v2 and so on, one per version after the first
+    pub migrations: &'static [Migration],
+}
+
+impl StateEnvelope {
+    pub fn encode<T: Serialize>(&self, state: &T) -> Result<Vec<u8>, String> {
+        let mut bytes = self.magic.to_vec();
+        bytes.extend(self.version.to_le_bytes());
+        bytes.extend(serde_json::to_vec(state).map_err(|e| e.to_string())?);
+        Ok(bytes)
+    }
+
+    /// Whether `bytes` were written through this envelope at all.
+    pub fn wraps(&self, bytes: &[u8]) -> bool {
+        bytes.starts_with(self.magic)
+    }
+
+    /// Decode a state of any version up to ours, migrating older ones.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        let rest = bytes.strip_prefix(self.magic).ok_or("state without an envelope")?;
+        let (version, payload) = rest.split_first_chunk::<4>().ok_or("state envelope without a version")?;
+        let version = u32::from_le_bytes(*version);
+        if version == 0 || version > self.version {
+            return Err(format!(
+                "state v{} is not readable by this build (v{})",
+                version, self.version
+            ));
+        }
+
+        let mut value: serde_json::Value = serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+        for migrate in self.migrations.iter().skip(version as usize - 1) {
+            value = migrate(value)?;
+        }
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,438 @@
+//! Wire protocol spoken between embedding providers and coordinators.
+//!
+//! All messages are JSON encoded `serde` values. Embeddings may instead
+//! travel in the message blob, see `EncodedEmbeddings`.
+//!
+//! # Versioning
+//!
+//! A provider announces the version it speaks in
+//! `CoordinatorRequest::RegisterProvider` and the coordinator answers with the
+//! version both sides will use for the rest of the binding in
+//! `CoordinatorResponse::ProviderRegistered`. Peers built before versioning
+//! existed send neither field and are treated as version 0.
+//!
+//! Compatibility rules:
+//! - Adding a field is allowed without a version bump as long as it carries
+//!   `#[serde(default)]`, so that older peers can omit it. Unknown fields are
+//!   ignored on decode, so older peers also accept it.
+//! - Adding a message variant, or changing or removing a field, requires a
+//!   bump of `PROTOCOL_VERSION`. The new variant must only be sent to peers
+//!   whose negotiated version is at least the one that introduced it.
+//! - `MIN_COMPATIBLE_VERSION` is raised only when support for an old version
+//!   is dropped on purpose.
+
+use serde::{Deserialize, Serialize};
+
+mod encoding;
+mod envelope;
+pub use encoding::{choose_encoding, EmbeddingEncoding, EncodedEmbeddings, SUPPORTED_ENCODINGS};
+pub use envelope::{Migration, StateEnvelope};
+
+/// Version spoken by this build.
+///
+/// - 0: unversioned protocol
+/// - 1: version handshake, coordinator peer discovery
+/// - 2: registration challenge
+/// - 3: health reports
+/// - 4: queue rejections
+/// - 5: batch assignments
+/// - 6: work input modalities
+/// - 7: acknowledged work reports
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// Oldest version this build can still talk to.
+pub const MIN_COMPATIBLE_VERSION: u32 = 0;
+
+/// First version that understands `ProviderResponse::HealthReport`.
+pub const MIN_HEALTH_REPORT_VERSION: u32 = 3;
+
+/// First version that understands `ProviderResponse::QueueFull` and
+/// `ProviderResponse::Busy`. Older peers are sent `ProviderResponse::Error`.
+pub const MIN_QUEUE_REJECTION_VERSION: u32 = 4;
+
+/// First version that accepts `ProviderRequest::AssignBatch`.
+pub const MIN_BATCH_VERSION: u32 = 5;
+
+/// First version that reads `WorkRequest::input`. Older peers only see `uri`,
+/// so anything but an image URI must not be sent to them.
+pub const MIN_MODALITY_VERSION: u32 = 6;
+
+/// First version whose coordinators answer `ProviderResponse::WorkCompleted`,
+/// `WorkFailed` and `BatchCompleted` with `CoordinatorResponse::Ack`, or
+/// `Nack` for a report they refuse. Duplicate reports are acknowledged again,
+/// so providers may resend until they see the answer.
+pub const MIN_REPORT_ACK_VERSION: u32 = 7;
+
+/// First version that answers `CoordinatorResponse::Challenge`. Coordinators
+/// should refuse to register providers below it.
+pub const MIN_HANDSHAKE_VERSION: u32 = 2;
+
+/// Pick the version to use with a peer speaking `theirs`.
+// MIN_COMPATIBLE_VERSION is 0 until an old version is retired
+#[allow(clippy::absurd_extreme_comparisons)]
+pub fn negotiate_version(theirs: u32) -> Result<u32, String> {
+    if theirs < MIN_COMPATIBLE_VERSION {
+        return Err(format!(
+            "protocol version {} is no longer supported, need at least {}",
+            theirs, MIN_COMPATIBLE_VERSION
+        ));
+    }
+    Ok(theirs.min(PROTOCOL_VERSION))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ProviderState {
+    Unbound,      // Online, no coordinator assigned
+    Idle,         // Online, connected to coordinator, ready for work
+    Offline,      // Offline
+    Working {     // Online, processing a request
+        request: WorkRequest,
+        progress: Option<u32>,
+    },
+    WorkingBatch { // Online, processing a batch of requests
+        batch: WorkBatch,
+        progress: Option<u32>,
+    },
+    Failed {      // Online, error state, includes reason. Still bound to coordinator
+        error: WorkError,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WorkRequest {
+    pub id: String,
+    pub model: String,
+    // Image URI, kept for peers below MIN_MODALITY_VERSION. Empty when
+    // `input` is something else
+    pub uri: String,
+    #[serde(default)]
+    pub input: Option<WorkInput>,
+    // Longest the provider may spend on the job, None for the model default
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    pub timestamp: u64,
+}
+
+impl WorkRequest {
+    /// What to embed, falling back to `uri` for requests without an input.
+    pub fn input(&self) -> WorkInput {
+        self.input.clone().unwrap_or_else(|| WorkInput::ImageUri { uri: self.uri.clone() })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Modality {
+    Image,
+    Text,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum WorkInput {
+    ImageUri { uri: String },
+    ImageBytes { mime: String, bytes: Vec<u8> },
+    Text { text: String },
+}
+
+impl WorkInput {
+    pub fn modality(&self) -> Modality {
+        match self {
+            WorkInput::ImageUri { .. } | WorkInput::ImageBytes { .. } => Modality::Image,
+            WorkInput::Text { .. } => Modality::Text,
+        }
+    }
+
+    /// Whether peers below MIN_MODALITY_VERSION can process it.
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, WorkInput::ImageUri { .. })
+    }
+}
+
+// Inputs a model can embed
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ModelCapabilities {
+    pub model: String,
+    pub modalities: Vec<Modality>,
+}
+
+impl ModelCapabilities {
+    pub fn supports(&self, modality: Modality) -> bool {
+        self.modalities.contains(&modality)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Quantization {
+    Fp32,
+    Fp16,
+    Int8,
+    Q4,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Normalization {
+    None,
+    L2,
+}
+
+// Everything needed to tell whether two providers produce the same embeddings
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ModelSpec {
+    pub id: String,
+    pub revision: String,
+    pub dimension: u32,
+    pub modalities: Vec<Modality>,
+    pub quantization: Quantization,
+    pub normalization: Normalization,
+    // Rough time to embed a single input once the model is warm
+    pub expected_runtime_ms: u64,
+}
+
+impl ModelSpec {
+    pub fn capabilities(&self) -> ModelCapabilities {
+        ModelCapabilities {
+            model: self.id.clone(),
+            modalities: self.modalities.clone(),
+        }
+    }
+}
+
+// Space an embedding lives in. Towers of the same model (e.g. CLIP image
+// and text) share one space, so results with equal `model` are comparable
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EmbeddingSpace {
+    pub model: String,
+    pub modality: Modality,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkResult {
+    pub id: String,
+    pub embeddings: Vec<f32>,
+    #[serde(default)]
+    pub space: Option<EmbeddingSpace>,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WorkError {
+    pub id: String,
+    pub error: String,
+    #[serde(default)]
+    pub kind: WorkErrorKind,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub enum WorkErrorKind {
+    // Anything the worker reported without more detail
+    #[default]
+    Other,
+    // The worker produced output that is not a usable embedding
+    InvalidEmbedding(EmbeddingViolation),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum EmbeddingViolation {
+    // Not a number, or for binary embeddings not decodable from `index` on
+    Malformed {
+        index: usize,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    NonFinite { index: usize },
+    Dimension { expected: u32, actual: u32 },
+    NotNormalized { norm: f32 },
+}
+
+impl std::fmt::Display for EmbeddingViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmbeddingViolation::Malformed { index, reason: None } => write!(f, "value {} is not a number", index),
+            EmbeddingViolation::Malformed { index, reason: Some(reason) } => {
+                write!(f, "values from {} on are not decodable: {}", index, reason)
+            }
+            EmbeddingViolation::NonFinite { index } => write!(f, "value {} is not finite", index),
+            EmbeddingViolation::Dimension { expected, actual } => {
+                write!(f, "expected {} dimensions, got {}", expected, actual)
+            }
+            EmbeddingViolation::NotNormalized { norm } => {
+                write!(f, "expected a unit vector, L2 norm is {}", norm)
+            }
+        }
+    }
+}
+
+// Many inputs for the same model, computed as one unit. Every result lands
+// in the model's embedding space
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WorkBatch {
+    pub id: String,
+    pub model: String,
+    pub items: Vec<BatchItem>,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BatchItem {
+    pub id: String,
+    pub uri: String,
+    #[serde(default)]
+    pub input: Option<WorkInput>,
+}
+
+impl BatchItem {
+    pub fn input(&self) -> WorkInput {
+        self.input.clone().unwrap_or_else(|| WorkInput::ImageUri { uri: self.uri.clone() })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ItemResult {
+    pub id: String,
+    pub outcome: ItemOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ItemOutcome {
+    Completed { embeddings: Vec<f32> },
+    Failed { error: String },
+}
+
+// One result per batch item, in no particular order
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BatchResult {
+    pub batch_id: String,
+    pub results: Vec<ItemResult>,
+    pub timestamp: u64,
+}
+
+// Unprompted messages from provider to coordinator
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CoordinatorRequest {
+    RegisterProvider {
+        supported_models: Vec<String>,
+        #[serde(default)]
+        protocol_version: u32,
+        // Per-model input support. Empty from peers below
+        // MIN_MODALITY_VERSION, whose models take images only
+        #[serde(default)]
+        capabilities: Vec<ModelCapabilities>,
+        // Full metadata of the offered models, empty from older peers
+        #[serde(default)]
+        models: Vec<ModelSpec>,
+        // Binary embedding encodings the provider can send, preferred first.
+        // Empty means JSON only
+        #[serde(default)]
+        encodings: Vec<EmbeddingEncoding>,
+    },
+    ProviderReady,
+    GoOffline,
+    GetPeers,
+    ChallengeResponse {
+        challenge_id: String,
+        outputs: Vec<ChallengeOutput>,
+    },
+}
+
+// Prompted responses from coordinator to provider
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CoordinatorResponse {
+    ProviderRegistered {
+        required_models: Vec<String>,
+        #[serde(default)]
+        protocol_version: u32,
+        // Encoding for results sent to this coordinator, None for JSON
+        #[serde(default)]
+        encoding: Option<EmbeddingEncoding>,
+    },
+    RegistrationRejected { reason: String },
+    // Prove each claimed model works before registration completes
+    Challenge {
+        challenge_id: String,
+        inputs: Vec<ChallengeInput>,
+    },
+    NoWorkAvailable,
+    Peers(Vec<CoordinatorInfo>),
+    Ack,
+    Nack,
+    Error(String),
+}
+
+// Known input the provider has to embed with `model`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChallengeInput {
+    pub model: String,
+    pub uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChallengeOutput {
+    pub model: String,
+    pub embeddings: Vec<f32>,
+}
+
+// A coordinator as advertised by one of its peers
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CoordinatorInfo {
+    pub address: String,
+    pub required_models: Vec<String>,
+}
+
+// Unprompted Coordinator to Provider messages
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub enum ProviderRequest {
+    HealthPing,
+    AssignWork(WorkRequest),
+    AssignBatch(WorkBatch),
+    Kick,
+}
+
+// Provider statistics sent in answer to a HealthPing
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HealthReport {
+    pub state: ProviderState,
+    pub uptime_secs: u64,
+    pub jobs_completed: u64,
+    pub jobs_failed: u64,
+    pub avg_latency_ms: Option<u64>,
+    pub p95_latency_ms: Option<u64>,
+    pub ui_channels: u32,
+    pub models: Vec<ModelStatus>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ModelStatus {
+    pub model: String,
+    pub loaded: bool,
+    pub warm: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ProviderResponse {
+    // Bare answer to a HealthPing, for peers below MIN_HEALTH_REPORT_VERSION
+    HealthPong,
+    HealthReport(HealthReport),
+    // Accepted, either started right away or queued behind the current job
+    WorkAssigned,
+    // Local job queue is at capacity
+    QueueFull { max_depth: u32 },
+    // Not accepting work at all, e.g. offline or no compute worker connected
+    Busy(String),
+    WorkCompleted {
+        result: WorkResult,
+        // Set when the embeddings travel in the blob, in which case
+        // `result.embeddings` is empty
+        #[serde(default)]
+        encoded: Option<EncodedEmbeddings>,
+    },
+    WorkFailed {
+        error: WorkError
+    },
+    BatchCompleted {
+        result: BatchResult
+    },
+    Error(String),
+}
@@ -0,0 +1,93 @@
+use protocol::{choose_encoding, EmbeddingEncoding, EncodedEmbeddings};
+
+fn round_trip(encoding: EmbeddingEncoding, values: &[f32]) -> Vec<f32> {
+    let (header, bytes) = encoding.encode(values);
+    assert_eq!(header.dimension as usize, values.len());
+    assert_eq!(bytes.len(), values.len() * encoding.bytes_per_value());
+    header.decode(&bytes).expect("encoded values do not decode")
+}
+
+#[test]
+fn f32_is_lossless() {
+    let values = [0.0, -0.0, 1.0, -0.25, f32::MIN_POSITIVE, 1e-45, f32::MAX, f32::INFINITY];
+    let decoded = round_trip(EmbeddingEncoding::F32Le, &values);
+    assert_eq!(
+        decoded.iter().map(|v| v.to_bits()).collect::<Vec<_>>(),
+        values.iter().map(|v| v.to_bits()).collect::<Vec<_>>(),
+    );
+    assert!(round_trip(EmbeddingEncoding::F32Le, &[f32::NAN])[0].is_nan());
+}
+
+#[test]
+fn f16_keeps_exactly_representable_values() {
+    let values = [0.0, 1.0, -2.0, 0.5, 65504.0, -0.099975586];
+    assert_eq!(round_trip(EmbeddingEncoding::F16Le, &values), values);
+    let negative_zero = round_trip(EmbeddingEncoding::F16Le, &[-0.0])[0];
+    assert_eq!(negative_zero.to_bits(), (-0.0f32).to_bits());
+}
+
+#[test]
+fn f16_rounds_to_the_nearest_half() {
+    for value in [0.1f32, -0.3, 0.7, 1.0 / 3.0, 1234.5678] {
+        let decoded = round_trip(EmbeddingEncoding::F16Le, &[value])[0];
+        // Half precision has 11 significant bits
+        assert!((decoded - value).abs() <= value.abs() * 2f32.powi(-11), "{value} became {decoded}");
+    }
+    // A carry out of the mantissa moves to the next exponent
+    assert_eq!(round_trip(EmbeddingEncoding::F16Le, &[2047.9]), [2048.0]);
+}
+
+#[test]
+fn f16_subnormals_survive() {
+    let smallest = 2f32.powi(-24);
+    let values = [smallest, -smallest, 3.0 * smallest, 2f32.powi(-15)];
+    assert_eq!(round_trip(EmbeddingEncoding::F16Le, &values), values);
+    // Below half the smallest subnormal there is only zero
+    assert_eq!(round_trip(EmbeddingEncoding::F16Le, &[2f32.powi(-26)]), [0.0]);
+}
+
+#[test]
+fn f16_overflow_and_nan() {
+    let decoded = round_trip(EmbeddingEncoding::F16Le, &[1e6, -1e6, f32::INFINITY, f32::NAN]);
+    assert_eq!(decoded[..3], [f32::INFINITY, f32::NEG_INFINITY, f32::INFINITY]);
+    assert!(decoded[3].is_nan());
+}
+
+#[test]
+fn int8_scales_to_the_largest_magnitude() {
+    let values = [1.0, -0.5, 0.25, 0.0];
+    let (header, bytes) = EmbeddingEncoding::Int8.encode(&values);
+    assert_eq!(header.scale, 1.0 / 127.0);
+    let decoded = header.decode(&bytes).unwrap();
+    for (value, decoded) in values.iter().zip(&decoded) {
+        assert!((value - decoded).abs() <= header.scale / 2.0, "{value} became {decoded}");
+    }
+}
+
+#[test]
+fn int8_of_zeros_is_zeros() {
+    let (header, bytes) = EmbeddingEncoding::Int8.encode(&[0.0; 4]);
+    assert_eq!(header.scale, 1.0);
+    assert_eq!(header.decode(&bytes).unwrap(), [0.0; 4]);
+}
+
+#[test]
+fn a_payload_of_the_wrong_length_is_refused() {
+    let header = EncodedEmbeddings {
+        encoding: EmbeddingEncoding::F16Le,
+        dimension: 3,
+        scale: 0.0,
+    };
+    assert!(header.decode(&[0; 5]).is_err());
+    assert!(header.decode(&[0; 8]).is_err());
+    assert_eq!(header.decode(&[0; 6]).unwrap(), [0.0; 3]);
+}
+
+#[test]
+fn the_first_supported_offer_wins() {
+    assert_eq!(
+        choose_encoding(&[EmbeddingEncoding::Int8, EmbeddingEncoding::F32Le]),
+        Some(EmbeddingEncoding::Int8),
+    );
+    assert_eq!(choose_encoding(&[]), None);
+}
@@ -0,0 +1,40 @@
+use protocol::StateEnvelope;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Counter {
+    count: u32,
+}
+
+const V1: StateEnvelope = StateEnvelope { magic: b"TEST", version: 1, migrations: &[] };
+
+// v2 renamed `value` to `count`
+const V2: StateEnvelope = StateEnvelope {
+    magic: b"TEST",
+    version: 2,
+    migrations: &[|mut value| {
+        let count = value.as_object_mut().and_then(|o| o.remove("value")).ok_or("no value")?;
+        Ok(serde_json::json!({ "count": count }))
+    }],
+};
+
+#[test]
+fn a_state_survives_the_round_trip() {
+    let bytes = V2.encode(&Counter { count: 3 }).unwrap();
+    assert!(V2.wraps(&bytes));
+    assert_eq!(V2.decode::<Counter>(&bytes).unwrap(), Counter { count: 3 });
+}
+
+#[test]
+fn an_older_state_is_migrated() {
+    let bytes = V1.encode(&serde_json::json!({ "value": 7 })).unwrap();
+    assert_eq!(V2.decode::<Counter>(&bytes).unwrap(), Counter { count: 7 });
+}
+
+#[test]
+fn a_newer_or_foreign_state_is_refused() {
+    let bytes = V2.encode(&Counter { count: 1 }).unwrap();
+    assert!(V1.decode::<Counter>(&bytes).is_err());
+    assert!(!V1.wraps(b"HPST\x01\0\0\0{}"));
+    assert!(V1.decode::<Counter>(b"TEST\x01").is_err());
+}
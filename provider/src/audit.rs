@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use kinode_process_lib::{Address, vfs};
+
+use crate::machine::{EventKind, StateKind};
+use crate::structs::{ProviderEvent, ProviderState};
+
+/// Drive the audit log lives in, under the provider's package.
+const AUDIT_DRIVE: &str = "audit";
+/// One JSON encoded `AuditEntry` per line.
+const AUDIT_FILE: &str = "transitions.jsonl";
+/// Entries kept in memory and across a compaction.
+pub const MAX_AUDIT_ENTRIES: usize = 1_000;
+/// Lines the file may grow to before it is cut back to MAX_AUDIT_ENTRIES.
+const COMPACT_AFTER_LINES: usize = 2 * MAX_AUDIT_ENTRIES;
+/// Timeout for a single vfs call.
+const VFS_TIMEOUT_SECS: u64 = 5;
+
+// One transition the state machine was asked to make
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    // Milliseconds since the epoch
+    pub timestamp: u64,
+    pub event: EventKind,
+    // The event with the ids it refers to, without payloads such as inputs
+    pub cause: String,
+    pub from: StateKind,
+    // Job the provider was working on before the event
+    pub job_id: Option<String>,
+    // None if the transition was rejected
+    pub to: Option<StateKind>,
+    pub rejected: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        previous: &ProviderState,
+        event: &ProviderEvent,
+        outcome: Result<&ProviderState, &str>,
+    ) -> Self {
+        let job_id = match previous {
+            ProviderState::Working { request, .. } => Some(request.id.clone()),
+            ProviderState::WorkingBatch { batch, .. } => Some(batch.id.clone()),
+            _ => None,
+        };
+        Self {
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            event: EventKind::of(event),
+            cause: describe(event),
+            from: StateKind::of(previous),
+            job_id,
+            to: outcome.ok().map(StateKind::of),
+            rejected: outcome.err().map(str::to_string),
+        }
+    }
+}
+
+fn describe(event: &ProviderEvent) -> String {
+    match event {
+        ProviderEvent::RegisterWithCoordinator(addr) => format!("RegisterWithCoordinator({})", addr),
+        ProviderEvent::StartWork(request) => format!("StartWork({})", request.id),
+        ProviderEvent::StartBatch(batch) => format!("StartBatch({}, {} items)", batch.id, batch.items.len()),
+        ProviderEvent::CompleteWork(result) => format!("CompleteWork({})", result.id),
+        ProviderEvent::CompleteBatch(result) => format!("CompleteBatch({})", result.batch_id),
+        ProviderEvent::FailWork { error } => format!("FailWork({}: {})", error.id, error.error),
+        ProviderEvent::TimeOut { error } => format!("TimeOut({}: {})", error.id, error.error),
+        ProviderEvent::UpdateProgress(progress) => format!("UpdateProgress({})", progress),
+        ProviderEvent::Kicked => "Kicked".to_string(),
+        ProviderEvent::GoOffline => "GoOffline".to_string(),
+        ProviderEvent::GoOnline(addr) => format!("GoOnline({})", addr),
+    }
+}
+
+/// Every accepted and rejected transition, for settling disputes with
+/// coordinators. Appended to the vfs and compacted to the newest
+/// MAX_AUDIT_ENTRIES on startup and once it reaches COMPACT_AFTER_LINES.
+/// Accepted progress updates are left out, they say nothing a dispute
+/// needs and would crowd out everything else.
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    // None until opened, entries are then only kept in memory
+    path: Option<String>,
+    entries: VecDeque<AuditEntry>,
+    // Lines in the file, counted from the last compaction
+    lines: usize,
+}
+
+impl AuditLog {
+    pub fn open(our: &Address) -> anyhow::Result<Self> {
+        let drive = vfs::create_drive(our.package_id(), AUDIT_DRIVE, Some(VFS_TIMEOUT_SECS))?;
+        let path = format!("{}/{}", drive, AUDIT_FILE);
+        let bytes = vfs::open_file(&path, true, Some(VFS_TIMEOUT_SECS))?.read()?;
+
+        let mut entries = VecDeque::new();
+        for line in bytes.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            // A line torn by a crash is not worth failing the log over
+            if let Ok(entry) = serde_json::from_slice(line) {
+                entries.push_back(entry);
+                if entries.len() > MAX_AUDIT_ENTRIES {
+                    entries.pop_front();
+                }
+            }
+        }
+
+        let mut log = Self {
+            path: Some(path),
+            entries,
+            lines: 0,
+        };
+        log.compact()?;
+        Ok(log)
+    }
+
+    /// Cut the file back to the entries kept in memory.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut compacted = Vec::new();
+        for entry in &self.entries {
+            compacted.extend(serde_json::to_vec(entry)?);
+            compacted.push(b'\n');
+        }
+        vfs::create_file(path, Some(VFS_TIMEOUT_SECS))?.write(&compacted)?;
+        self.lines = self.entries.len();
+        Ok(())
+    }
+
+    /// Keep an entry. A failed write is logged, never fatal.
+    pub fn record(&mut self, entry: AuditEntry) {
+        if entry.event == EventKind::UpdateProgress && entry.rejected.is_none() {
+            return;
+        }
+        if let Some(path) = &self.path {
+            let result = serde_json::to_vec(&entry)
+                .map_err(anyhow::Error::from)
+                .and_then(|mut line| {
+                    line.push(b'\n');
+                    vfs::open_file(path, true, Some(VFS_TIMEOUT_SECS))?.append(&line)?;
+                    Ok(())
+                });
+            match result {
+                Ok(()) => self.lines += 1,
+                Err(e) => kiprintln!("failed to write audit log: {e}"),
+            }
+        }
+        self.entries.push_back(entry);
+        if self.entries.len() > MAX_AUDIT_ENTRIES {
+            self.entries.pop_front();
+        }
+        if self.lines >= COMPACT_AFTER_LINES {
+            if let Err(e) = self.compact() {
+                kiprintln!("failed to compact audit log: {e}");
+            }
+        }
+    }
+
+    /// The newest `limit` entries, oldest first.
+    pub fn recent(&self, limit: usize, rejected_only: bool) -> Vec<&AuditEntry> {
+        let mut entries: Vec<&AuditEntry> = self.entries.iter()
+            .rev()
+            .filter(|entry| !rejected_only || entry.rejected.is_some())
+            .take(limit)
+            .collect();
+        entries.reverse();
+        entries
+    }
+}
@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use kinode_process_lib::{Address, vfs};
+
+use crate::structs::{CoordinatorInfo, CoordinatorRequest, CoordinatorResponse, PendingContext};
+use crate::transport::Transport;
+
+/// Seed file shipped in the package's `pkg` drive.
+const SEED_FILE: &str = "coordinators.json";
+/// Time between two peer discovery rounds.
+const REFRESH_INTERVAL_SECS: u64 = 300;
+/// Timeout for a single GetPeers request.
+const PEERS_TIMEOUT_SECS: u64 = 5;
+/// Upper bound on the registry so a misbehaving coordinator cannot flood it.
+const MAX_KNOWN_COORDINATORS: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct KnownCoordinator {
+    pub address: Address,
+    pub required_models: Vec<String>,
+    pub last_seen: Option<u64>,
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CoordinatorRegistry {
+    coordinators: Vec<KnownCoordinator>,
+    last_refresh: u64,
+}
+
+impl CoordinatorRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the seed list from `/<package>/pkg/coordinators.json`.
+    /// Missing or malformed entries are logged and skipped.
+    pub fn seed_from_config(&mut self, our: &Address) -> anyhow::Result<usize> {
+        let path = format!("/{}/pkg/{}", our.package_id(), SEED_FILE);
+        let contents = vfs::open_file(&path, false, Some(5))?.read_to_string()?;
+        let seeds: Vec<CoordinatorInfo> = serde_json::from_str(&contents)?;
+        Ok(self.merge(seeds))
+    }
+
+    /// Insert a coordinator or update its required models.
+    /// Returns true if it was not known before.
+    pub fn upsert(&mut self, address: Address, required_models: Vec<String>) -> bool {
+        if let Some(known) = self.coordinators.iter_mut().find(|c| c.address == address) {
+            known.required_models = required_models;
+            return false;
+        }
+        if self.coordinators.len() >= MAX_KNOWN_COORDINATORS {
+            kiprintln!("coordinator registry full, ignoring {}", address);
+            return false;
+        }
+        self.coordinators.push(KnownCoordinator {
+            address,
+            required_models,
+            last_seen: None,
+            latency_ms: None,
+        });
+        true
+    }
+
+    pub fn mark_seen(&mut self, address: &Address, latency_ms: Option<u64>) {
+        if let Some(known) = self.coordinators.iter_mut().find(|c| &c.address == address) {
+            known.last_seen = Some(now());
+            if latency_ms.is_some() {
+                known.latency_ms = latency_ms;
+            }
+        }
+    }
+
+    /// Time until the next discovery round is due, zero if it is overdue.
+    pub fn next_refresh_ms(&self) -> u64 {
+        let due = self.last_refresh.saturating_add(REFRESH_INTERVAL_SECS);
+        due.saturating_sub(now()).saturating_mul(1000)
+    }
+
+    /// Ask every known coordinator for its peers without waiting, the
+    /// answers come back through `peers_answered` and `peers_missing`.
+    /// Returns the number of coordinators asked.
+    pub fn refresh(&mut self, transport: &mut dyn Transport) -> anyhow::Result<usize> {
+        let body = serde_json::to_vec(&CoordinatorRequest::GetPeers)?;
+        for known in &self.coordinators {
+            let context = PendingContext::Peers {
+                coordinator: known.address.clone(),
+                sent_at_ms: now_ms(),
+            };
+            transport.send(
+                &known.address,
+                body.clone(),
+                None,
+                Some((PEERS_TIMEOUT_SECS, serde_json::to_vec(&context)?)),
+            )?;
+        }
+        self.last_refresh = now();
+        Ok(self.coordinators.len())
+    }
+
+    /// Merge a GetPeers answer sent at `sent_at_ms`. Returns the number of
+    /// newly discovered coordinators.
+    pub fn peers_answered(&mut self, address: &Address, body: &[u8], sent_at_ms: u64) -> usize {
+        self.mark_seen(address, Some(now_ms().saturating_sub(sent_at_ms)));
+        match serde_json::from_slice(body) {
+            Ok(CoordinatorResponse::Peers(peers)) => self.merge(peers),
+            _ => {
+                kiprintln!("coordinator {} sent an unexpected GetPeers response", address);
+                0
+            }
+        }
+    }
+
+    /// Forget a coordinator that did not answer GetPeers, so dead addresses
+    /// do not pile up. A live one comes back through its peers or the seed.
+    pub fn peers_missing(&mut self, address: &Address) {
+        kiprintln!("coordinator {} did not answer GetPeers, forgetting it", address);
+        self.coordinators.retain(|c| &c.address != address);
+    }
+
+    /// Known coordinators, optionally restricted to those requiring `model`,
+    /// most recently responsive first.
+    pub fn list(&self, model: Option<&str>) -> Vec<&KnownCoordinator> {
+        let mut coordinators: Vec<&KnownCoordinator> = self.coordinators.iter()
+            .filter(|c| model.is_none_or(|m| c.required_models.iter().any(|r| r == m)))
+            .collect();
+        coordinators.sort_by_key(|c| (c.last_seen.is_none(), c.latency_ms.unwrap_or(u64::MAX)));
+        coordinators
+    }
+
+    fn merge(&mut self, infos: Vec<CoordinatorInfo>) -> usize {
+        let mut added = 0;
+        for info in infos {
+            match Address::from_str(&info.address) {
+                Ok(address) => {
+                    if self.upsert(address, info.required_models) {
+                        added += 1;
+                    }
+                }
+                Err(e) => kiprintln!("skipping invalid coordinator address {:?}: {e}", info.address),
+            }
+        }
+        added
+    }
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
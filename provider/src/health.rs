@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::structs::{HealthReport, ModelStatus, ProviderState};
+
+/// Number of recent jobs the latency figures are computed over.
+const LATENCY_WINDOW: usize = 100;
+
+/// Counters behind the HealthReport sent to the coordinator.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HealthStats {
+    pub started_at: u64,
+    pub jobs_completed: u64,
+    pub jobs_failed: u64,
+    pub last_error: Option<String>,
+    latencies_ms: VecDeque<u64>,
+    job_started_at_ms: Option<u64>,
+    models: Vec<ModelStatus>,
+}
+
+impl HealthStats {
+    pub fn new() -> Self {
+        let mut stats = Self::default();
+        stats.process_started();
+        stats
+    }
+
+    /// Reset what does not survive a restart: uptime and loaded models.
+    pub fn process_started(&mut self) {
+        self.started_at = now_ms() / 1000;
+        self.job_started_at_ms = None;
+        self.models.clear();
+    }
+
+    pub fn job_started(&mut self) {
+        self.job_started_at_ms = Some(now_ms());
+    }
+
+    pub fn job_completed(&mut self) {
+        self.jobs_completed += 1;
+        if let Some(started) = self.job_started_at_ms.take() {
+            if self.latencies_ms.len() == LATENCY_WINDOW {
+                self.latencies_ms.pop_front();
+            }
+            self.latencies_ms.push_back(now_ms().saturating_sub(started));
+        }
+    }
+
+    pub fn job_failed(&mut self, error: &str) {
+        self.jobs_failed += 1;
+        self.job_started_at_ms = None;
+        self.last_error = Some(error.to_string());
+    }
+
+    /// Model status as reported by the UI doing the compute.
+    pub fn set_model_status(&mut self, status: ModelStatus) {
+        match self.models.iter_mut().find(|m| m.model == status.model) {
+            Some(known) => *known = status,
+            None => self.models.push(status),
+        }
+    }
+
+    /// Forget model status once no UI is left to run them.
+    pub fn clear_models(&mut self) {
+        self.models.clear();
+    }
+
+    pub fn report(
+        &self,
+        state: &ProviderState,
+        ui_channels: usize,
+        supported_models: &[String],
+    ) -> HealthReport {
+        let mut sorted: Vec<u64> = self.latencies_ms.iter().copied().collect();
+        sorted.sort_unstable();
+
+        let avg_latency_ms = (!sorted.is_empty())
+            .then(|| sorted.iter().sum::<u64>() / sorted.len() as u64);
+        let p95_latency_ms = (!sorted.is_empty())
+            .then(|| sorted[(sorted.len() * 95).div_ceil(100) - 1]);
+
+        let models = supported_models.iter()
+            .map(|model| {
+                self.models.iter()
+                    .find(|m| &m.model == model)
+                    .cloned()
+                    .unwrap_or(ModelStatus {
+                        model: model.clone(),
+                        loaded: false,
+                        warm: false,
+                    })
+            })
+            .collect();
+
+        HealthReport {
+            state: state.clone(),
+            uptime_secs: (now_ms() / 1000).saturating_sub(self.started_at),
+            jobs_completed: self.jobs_completed,
+            jobs_failed: self.jobs_failed,
+            avg_latency_ms,
+            p95_latency_ms,
+            ui_channels: ui_channels as u32,
+            models,
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
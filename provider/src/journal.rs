@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+use kinode_process_lib::{Address, vfs};
+
+use crate::structs::{Assignment, BatchResult, ProviderResponse, WorkError, WorkResult};
+
+/// Drive the journal lives in, under the provider's package.
+const JOURNAL_DRIVE: &str = "journal";
+/// One JSON encoded `JournalEntry` per line.
+const JOURNAL_FILE: &str = "jobs.jsonl";
+/// Timeout for a single vfs call.
+const VFS_TIMEOUT_SECS: u64 = 5;
+/// Lines the journal may grow to before settled jobs are dropped from it.
+const COMPACT_AFTER_LINES: usize = 1_000;
+/// Progress is journaled in steps of this many percent, not on every tick.
+pub const PROGRESS_STEP: u32 = 10;
+
+// How a job ended, as reported to the coordinator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum JobOutcome {
+    Completed(WorkResult),
+    BatchCompleted(BatchResult),
+    Failed(WorkError),
+}
+
+impl JobOutcome {
+    pub fn job_id(&self) -> &str {
+        match self {
+            JobOutcome::Completed(result) => &result.id,
+            JobOutcome::BatchCompleted(result) => &result.batch_id,
+            JobOutcome::Failed(error) => &error.id,
+        }
+    }
+
+    /// The report for the coordinator, embeddings as plain JSON.
+    pub fn response(&self) -> ProviderResponse {
+        match self {
+            JobOutcome::Completed(result) => ProviderResponse::WorkCompleted {
+                result: result.clone(),
+                encoded: None,
+            },
+            JobOutcome::BatchCompleted(result) => ProviderResponse::BatchCompleted {
+                result: result.clone(),
+            },
+            JobOutcome::Failed(error) => ProviderResponse::WorkFailed {
+                error: error.clone(),
+            },
+        }
+    }
+}
+
+// One line of the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum JournalEntry {
+    // Accepted from the coordinator, queued or started
+    Assigned(Assignment),
+    Progress { job_id: String, progress: u32 },
+    // Written before the outcome is sent
+    Finished(JobOutcome),
+    // The coordinator acknowledged the outcome, or for coordinators below
+    // MIN_REPORT_ACK_VERSION it was sent
+    Reported { job_id: String },
+    // The outcome was never acknowledged and we stopped resending it
+    Abandoned { job_id: String },
+}
+
+/// Everything the journal knows about one job.
+#[derive(Debug, Clone)]
+pub struct JobRecord {
+    pub assignment: Assignment,
+    pub progress: Option<u32>,
+    pub outcome: Option<JobOutcome>,
+    pub reported: bool,
+    pub abandoned: bool,
+}
+
+impl JobRecord {
+    pub fn id(&self) -> &str {
+        self.assignment.id()
+    }
+
+    /// Nothing left to do: the outcome was reported or given up on.
+    pub fn is_settled(&self) -> bool {
+        self.outcome.is_some() && (self.reported || self.abandoned)
+    }
+
+    /// The entries needed to rebuild this record.
+    fn entries(&self) -> Vec<JournalEntry> {
+        let mut entries = vec![JournalEntry::Assigned(self.assignment.clone())];
+        if let Some(progress) = self.progress {
+            entries.push(JournalEntry::Progress { job_id: self.id().to_string(), progress });
+        }
+        if let Some(outcome) = &self.outcome {
+            entries.push(JournalEntry::Finished(outcome.clone()));
+        }
+        if self.reported {
+            entries.push(JournalEntry::Reported { job_id: self.id().to_string() });
+        }
+        if self.abandoned {
+            entries.push(JournalEntry::Abandoned { job_id: self.id().to_string() });
+        }
+        entries
+    }
+}
+
+/// Append-only record of assignments, progress and outcomes in the vfs, so
+/// jobs in flight survive a restart. Compacted on startup and whenever it
+/// reaches COMPACT_AFTER_LINES.
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    // None until opened, recording is then a no-op
+    path: Option<String>,
+    // Lines in the file, counted from the last rewrite
+    lines: usize,
+}
+
+impl Journal {
+    pub fn open(our: &Address) -> anyhow::Result<Self> {
+        let drive = vfs::create_drive(our.package_id(), JOURNAL_DRIVE, Some(VFS_TIMEOUT_SECS))?;
+        Ok(Self {
+            path: Some(format!("{}/{}", drive, JOURNAL_FILE)),
+            lines: 0,
+        })
+    }
+
+    /// Append an entry. A failed write is logged, never fatal: losing the
+    /// journal is better than losing the job.
+    pub fn record(&mut self, entry: JournalEntry) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = serde_json::to_vec(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                vfs::open_file(path, true, Some(VFS_TIMEOUT_SECS))?.append(&line)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            kiprintln!("failed to write job journal: {e}");
+            return;
+        }
+        self.lines += 1;
+        if self.lines >= COMPACT_AFTER_LINES {
+            if let Err(e) = self.compact() {
+                kiprintln!("failed to compact job journal: {e}");
+            }
+        }
+    }
+
+    /// Drop the jobs that are settled, keeping everything still in flight.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let open: Vec<JobRecord> = self.replay()?.into_iter()
+            .filter(|record| !record.is_settled())
+            .collect();
+        self.rewrite(&open)
+    }
+
+    /// The journal folded into one record per job, see `fold`.
+    pub fn replay(&self) -> anyhow::Result<Vec<JobRecord>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        let bytes = vfs::open_file(path, true, Some(VFS_TIMEOUT_SECS))?.read()?;
+        Ok(fold(&bytes))
+    }
+
+    /// Replace the journal with just the given records.
+    pub fn rewrite(&mut self, records: &[JobRecord]) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let bytes = encode(records)?;
+        vfs::create_file(path, Some(VFS_TIMEOUT_SECS))?.write(&bytes)?;
+        self.lines = bytes.iter().filter(|b| **b == b'\n').count();
+        Ok(())
+    }
+}
+
+/// The journal lines that rebuild `records`.
+pub fn encode(records: &[JobRecord]) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for entry in records.iter().flat_map(JobRecord::entries) {
+        bytes.extend(serde_json::to_vec(&entry)?);
+        bytes.push(b'\n');
+    }
+    Ok(bytes)
+}
+
+/// Fold journal lines into one record per job, in assignment order. Lines
+/// that do not parse, such as one torn by a crash, are skipped.
+pub fn fold(bytes: &[u8]) -> Vec<JobRecord> {
+    let mut records: Vec<JobRecord> = Vec::new();
+    for line in bytes.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+        let entry: JournalEntry = match serde_json::from_slice(line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                kiprintln!("skipping unreadable journal entry: {e}");
+                continue;
+            }
+        };
+        match entry {
+            JournalEntry::Assigned(assignment) => {
+                // A reassigned id starts over
+                records.retain(|r| r.id() != assignment.id());
+                records.push(JobRecord {
+                    assignment,
+                    progress: None,
+                    outcome: None,
+                    reported: false,
+                    abandoned: false,
+                });
+            }
+            JournalEntry::Progress { job_id, progress } => {
+                if let Some(record) = records.iter_mut().find(|r| r.id() == job_id) {
+                    record.progress = Some(progress);
+                }
+            }
+            JournalEntry::Finished(outcome) => {
+                if let Some(record) = records.iter_mut().find(|r| r.id() == outcome.job_id()) {
+                    record.outcome = Some(outcome);
+                }
+            }
+            JournalEntry::Reported { job_id } => {
+                if let Some(record) = records.iter_mut().find(|r| r.id() == job_id) {
+                    record.reported = true;
+                }
+            }
+            JournalEntry::Abandoned { job_id } => {
+                if let Some(record) = records.iter_mut().find(|r| r.id() == job_id) {
+                    record.abandoned = true;
+                }
+            }
+        }
+    }
+    records
+}
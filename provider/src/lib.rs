@@ -1,25 +1,62 @@
 use std::str::FromStr;
 
+#[cfg(not(test))]
+use kinode_process_lib::call_init;
 use kinode_process_lib::{
-    kiprintln, await_message, 
-    println, call_init, get_blob,
-    Address, LazyLoadBlob, Message, Request, Response,
+    println,
+    Address, LazyLoadBlob, SendErrorKind,
     http::{
         self, 
         server::{
             HttpServer, 
             HttpBindingConfig, 
             WsMessageType, 
-            HttpServerRequest
         }
     },
 };
 
-use std::collections::HashSet;
+// Log to the node's terminal. There is no runtime under `cargo test`, so
+// print to stdout there
+macro_rules! kiprintln {
+    ($($arg:tt)*) => {{
+        #[cfg(not(test))]
+        kinode_process_lib::kiprintln!($($arg)*);
+        #[cfg(test)]
+        std::println!($($arg)*);
+    }};
+}
 
+mod audit;
+mod coordinators;
+mod health;
+mod journal;
+mod machine;
+mod models;
+mod outbox;
+mod persist;
+mod recovery;
 mod structs;
+mod transport;
+mod ui_messages;
+mod validation;
+mod workers;
+#[cfg(test)]
+mod tests;
 use structs::*;
+use audit::{AuditLog, MAX_AUDIT_ENTRIES};
+use journal::{JobOutcome, Journal, JournalEntry};
+use outbox::{DELIVERY_TIMEOUT_SECS, MAX_DELIVERY_ATTEMPTS};
+use recovery::Recovery;
+use transport::{HttpRequest, Incoming, KinodeTransport, Transport};
+use ui_messages::{
+    push_to_channel, BatchItemReport, ProviderPush, RegistrationResult, UiMessage, WebSocketMessage,
+};
+use validation::{decode_embedding_frame, validate_embeddings};
+use workers::{WorkerPool, WorkerStatus, RECONNECT_GRACE_MS};
 
+// The bindings come from the wit kit puts in target/, which a plain
+// `cargo test` checkout does not have. Tests never call into the runtime
+#[cfg(not(test))]
 wit_bindgen::generate!({
     path: "target/wit",
     world: "provider-template-dot-os-v0",
@@ -28,372 +65,1578 @@ wit_bindgen::generate!({
 });
 
 
+fn send_work_response(
+    state: &State,
+    transport: &mut dyn Transport,
+    response: ProviderResponse,
+) -> anyhow::Result<()> {
+    // Older coordinators only know the generic error variant
+    let response = match response {
+        ProviderResponse::QueueFull { max_depth }
+            if state.protocol_version < protocol::MIN_QUEUE_REJECTION_VERSION =>
+        {
+            ProviderResponse::Error(format!("queue full ({} jobs)", max_depth))
+        }
+        ProviderResponse::Busy(reason)
+            if state.protocol_version < protocol::MIN_QUEUE_REJECTION_VERSION =>
+        {
+            ProviderResponse::Error(reason)
+        }
+        response => response,
+    };
+    transport.respond(serde_json::to_vec(&response)?)
+}
+
 fn handle_work_request(
     state: &mut State,
-    channel_ids: &HashSet<u32>,
-    work_request: WorkRequest,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+    assignment: Assignment,
 ) -> anyhow::Result<()> {
+    match state.state {
+        ProviderState::Unbound | ProviderState::Offline => {
+            kiprintln!("rejecting work {}: not online", assignment.id());
+            return send_work_response(state, transport, ProviderResponse::Busy("provider is offline".to_string()));
+        }
+        // About to go offline, see schedule_recovery
+        _ if state.recovery.exhausted(&state.settings.recovery) => {
+            kiprintln!("rejecting work {}: too many failed jobs", assignment.id());
+            return send_work_response(state, transport, ProviderResponse::Busy("too many failed jobs".to_string()));
+        }
+        _ if !workers.has_capable() => {
+            kiprintln!("rejecting work {}: no compute worker", assignment.id());
+            return send_work_response(state, transport, ProviderResponse::Busy("no compute worker connected".to_string()));
+        }
+        _ if !assignment.inputs().iter().all(|input| state.models.supports(assignment.model(), input.modality())) => {
+            kiprintln!("rejecting work {}: unsupported input for {}", assignment.id(), assignment.model());
+            return send_work_response(state, transport, ProviderResponse::Error(format!(
+                "model {} does not support the requested input modality",
+                assignment.model()
+            )));
+        }
+        ProviderState::Working { .. } | ProviderState::WorkingBatch { .. }
+            if state.job_queue.len() >= state.settings.max_queue_depth =>
+        {
+            kiprintln!("rejecting work {}: queue full", assignment.id());
+            return send_work_response(state, transport, ProviderResponse::QueueFull {
+                max_depth: state.settings.max_queue_depth as u32,
+            });
+        }
+        _ => {}
+    }
 
     // create and send back a ProviderResponse::WorkAssigned and require no response
-    let response = ProviderResponse::WorkAssigned;
-
     kiprintln!("sending work assigned response to coordinator");
-    Response::new()
-        .body(serde_json::to_vec(&response)?)
-        .send()?;
-
-    let work_message = serde_json::json!({
-        "type": "work_request",
-        "data": {
-            "id": work_request.id,
-            "uri": work_request.uri,
-            "model": work_request.model,
-            "timestamp": work_request.timestamp,
+    send_work_response(state, transport, ProviderResponse::WorkAssigned)?;
+    state.journal.record(JournalEntry::Assigned(assignment.clone()));
+
+    if let ProviderState::Working { .. } | ProviderState::WorkingBatch { .. } = state.state {
+        kiprintln!("queueing work {} ({} waiting)", assignment.id(), state.job_queue.len() + 1);
+        state.job_queue.push_back(assignment);
+        return save_state(transport, state);
+    }
+
+    start_job(state, workers, transport, assignment)
+}
+
+/// Hand a job to one worker from the pool and move to Working.
+fn start_job(
+    state: &mut State,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+    assignment: Assignment,
+) -> anyhow::Result<()> {
+    workers.release_except(None);
+    let Some(channel_id) = workers.pick() else {
+        // Keep it at the front until a capable worker connects
+        kiprintln!("no worker available for {}, keeping it queued", assignment.id());
+        state.job_queue.push_front(assignment);
+        return save_state(transport, state);
+    };
+    workers.assign(channel_id, assignment.id());
+    let timeout_ms = state.job_timeout_ms(&assignment);
+    transport.set_timer(
+        timeout_ms,
+        serde_json::to_vec(&PendingContext::JobDeadline {
+            job_id: assignment.id().to_string(),
+        })?,
+    );
+
+    let (work_message, event) = match assignment {
+        Assignment::Single(work_request) => (
+            ProviderPush::work(&work_request),
+            ProviderEvent::StartWork(work_request),
+        ),
+        // The whole batch goes out as one message so the model warms up once
+        Assignment::Batch(batch) => (
+            ProviderPush::batch(&batch, &batch.items),
+            ProviderEvent::StartBatch(batch),
+        ),
+    };
+
+    push_to_channel(transport, channel_id, &work_message)?;
+    state.safe_transition(transport, event)?;
+    state.safe_transition(transport, ProviderEvent::UpdateProgress(0))
+}
+
+/// Send the current job to another worker after its worker went away.
+/// Items of a batch that already have a result are not sent again.
+/// Returns false if no other worker can take it.
+fn reassign_job(
+    state: &mut State,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+) -> anyhow::Result<bool> {
+    let Some(job_id) = state.current_job_id().map(str::to_string) else {
+        return Ok(true);
+    };
+    let Some(channel_id) = workers.pick() else {
+        return Ok(false);
+    };
+
+    let message = match &state.state {
+        ProviderState::Working { request, .. } => ProviderPush::work(request),
+        ProviderState::WorkingBatch { batch, .. } => {
+            let done = state.batch_run.as_ref().map(|run| &run.results);
+            let remaining: Vec<BatchItem> = batch.items.iter()
+                .filter(|item| !done.is_some_and(|done| done.iter().any(|r| r.id == item.id)))
+                .cloned()
+                .collect();
+            ProviderPush::batch(batch, &remaining)
         }
-    });
+        _ => return Ok(true),
+    };
+
+    kiprintln!("reassigning {} to worker {}", job_id, channel_id);
+    workers.assign(channel_id, &job_id);
+    push_to_channel(transport, channel_id, &message)?;
+    Ok(true)
+}
+
+/// Start the next queued job once the current one has finished.
+fn dispatch_next_job(
+    state: &mut State,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+) -> anyhow::Result<()> {
+    workers.release_except(state.current_job_id());
+    if state.state != ProviderState::Idle || !workers.has_capable() {
+        return Ok(());
+    }
+    // About to go offline, see schedule_recovery
+    if state.recovery.exhausted(&state.settings.recovery) {
+        return Ok(());
+    }
+    let Some(assignment) = state.job_queue.pop_front() else {
+        return Ok(());
+    };
+    kiprintln!("dispatching queued work {} ({} left)", assignment.id(), state.job_queue.len());
+    start_job(state, workers, transport, assignment)
+}
+
+/// Push the state to every tab if it changed, once per handled message
+/// however many transitions the message caused.
+fn publish_state(
+    state: &mut State,
+    workers: &WorkerPool,
+    transport: &mut dyn Transport,
+) -> anyhow::Result<()> {
+    if !state.unpublished {
+        return Ok(());
+    }
+    state.unpublished = false;
+    for channel_id in workers.channel_ids() {
+        notify_ui_state_change(transport, state, &channel_id)?;
+    }
+    Ok(())
+}
 
-    for &channel_id in channel_ids {
-        kiprintln!("Sending work message to channel {}", channel_id);
-        http::server::send_ws_push(
-            channel_id,
-            WsMessageType::Text,
-            LazyLoadBlob {
-                mime: Some("application/json".to_string()),
-                bytes: serde_json::to_vec(&work_message)?,
-            },
-        );
-        //state.safe_transition(ProviderEvent::StartWork(work_message), channel_id)?;
-        state.safe_transition(ProviderEvent::StartWork(work_request.clone()), channel_id)?;
-        state.safe_transition(ProviderEvent::UpdateProgress(0), channel_id)?;
+/// Stop taking work: fail the queue back to the coordinator and sign off.
+fn go_offline(state: &mut State, transport: &mut dyn Transport) -> anyhow::Result<()> {
+    fail_queued_jobs(state, transport, "provider went offline")?;
+    state.safe_transition(transport, ProviderEvent::GoOffline)?;
+    // Offline whether or not the coordinator hears of it, an unreachable
+    // one must not keep us taking work or hold up the event loop
+    let Some(coordinator) = state.coordinator.clone() else {
+        kiprintln!("no coordinator, going offline");
+        return Ok(());
+    };
+    let body = serde_json::to_vec(&CoordinatorRequest::GoOffline)?;
+    if let Err(e) = transport.send(&coordinator, body, None, None) {
+        kiprintln!("could not tell coordinator {} we are offline: {e}", coordinator);
+    }
+    Ok(())
+}
+
+/// Plan the way out of Failed, or go offline once jobs keep failing.
+fn schedule_recovery(state: &mut State, transport: &mut dyn Transport) -> anyhow::Result<()> {
+    let idle = matches!(state.state, ProviderState::Idle | ProviderState::Failed { .. });
+    if idle && state.recovery.exhausted(&state.settings.recovery) {
+        kiprintln!("{} jobs failed in a row, going offline", state.recovery.consecutive_failures);
+        go_offline(state, transport)?;
+        // Coming back online starts a fresh streak
+        state.recovery = Recovery::new();
+        return save_state(transport, state);
+    }
+    if !matches!(state.state, ProviderState::Failed { .. }) || state.recovery.scheduled {
+        return Ok(());
+    }
+
+    let delay = state.recovery.backoff_ms(&state.settings.recovery);
+    kiprintln!("recovering in {}ms (attempt {})", delay, state.recovery.attempt + 1);
+    state.recovery.scheduled = true;
+    transport.set_timer(delay, serde_json::to_vec(&PendingContext::Recover)?);
+    save_state(transport, state)
+}
+
+/// One attempt at leaving Failed: check the coordinator still has us, warm
+/// the model of the failed job back up and take work again.
+fn recover(
+    state: &mut State,
+    workers: &WorkerPool,
+    transport: &mut dyn Transport,
+) -> anyhow::Result<()> {
+    state.recovery.scheduled = false;
+    if !matches!(state.state, ProviderState::Failed { .. }) {
+        // Left Failed another way, e.g. by taking a new job
+        state.recovery.recovered();
+        return Ok(());
+    }
+    let Some(coordinator) = state.coordinator.clone() else {
+        kiprintln!("failed without a coordinator, unbinding");
+        state.recovery.recovered();
+        return state.safe_transition(transport, ProviderEvent::Kicked);
+    };
+
+    let response = transport.call(&coordinator, serde_json::to_vec(&CoordinatorRequest::ProviderReady)?, 5)?
+        .ok()
+        .and_then(|body| serde_json::from_slice(&body).ok());
+    match response {
+        Some(CoordinatorResponse::Ack) => {
+            if let Some(model) = &state.recovery.model {
+                let warm = ProviderPush::WarmModel { model: model.clone() };
+                for channel_id in workers.channel_ids() {
+                    push_to_channel(transport, channel_id, &warm)?;
+                }
+            }
+            kiprintln!("recovered, still bound to {}", coordinator);
+            state.recovery.recovered();
+            state.safe_transition(transport, ProviderEvent::GoOnline(coordinator))
+        }
+        Some(CoordinatorResponse::Nack) => {
+            kiprintln!("coordinator {} dropped us while we were failed", coordinator);
+            state.recovery.recovered();
+            state.safe_transition(transport, ProviderEvent::Kicked)
+        }
+        _ => {
+            // schedule_recovery tries again with a longer backoff
+            kiprintln!("recovery attempt {} failed: no answer from {}", state.recovery.attempt + 1, coordinator);
+            state.recovery.attempt += 1;
+            save_state(transport, state)
+        }
+    }
+}
+
+/// Journal a job's outcome, then report it to the coordinator. From
+/// MIN_REPORT_ACK_VERSION on it stays in the outbox until acknowledged.
+fn report_outcome(
+    state: &mut State,
+    transport: &mut dyn Transport,
+    outcome: JobOutcome,
+) -> anyhow::Result<()> {
+    let Some(coordinator) = state.coordinator.clone() else {
+        return Ok(());
+    };
+    let job_id = outcome.job_id().to_string();
+    state.journal.record(JournalEntry::Finished(outcome.clone()));
+
+    if state.protocol_version < protocol::MIN_REPORT_ACK_VERSION {
+        let (body, blob) = report_request(state, &coordinator, &outcome)?;
+        transport.send(&coordinator, body, blob, None)?;
+        state.journal.record(JournalEntry::Reported { job_id });
+        return Ok(());
+    }
+    state.outbox.push(coordinator, outcome);
+    send_delivery(state, transport, &job_id)
+}
+
+/// Body and blob of the report of an outcome. Results for the bound
+/// coordinator use the negotiated encoding, anything else goes as plain JSON.
+fn report_request(
+    state: &State,
+    coordinator: &Address,
+    outcome: &JobOutcome,
+) -> anyhow::Result<(Vec<u8>, Option<LazyLoadBlob>)> {
+    let encoding = state.embedding_encoding
+        .filter(|_| state.coordinator.as_ref() == Some(coordinator));
+    let (JobOutcome::Completed(result), Some(encoding)) = (outcome, encoding) else {
+        return Ok((serde_json::to_vec(&outcome.response())?, None));
+    };
+
+    let (encoded, bytes) = encoding.encode(&result.embeddings);
+    let mut report = result.clone();
+    report.embeddings.clear();
+    Ok((
+        serde_json::to_vec(&ProviderResponse::WorkCompleted {
+            result: report,
+            encoded: Some(encoded),
+        })?,
+        Some(LazyLoadBlob {
+            mime: Some("application/octet-stream".to_string()),
+            bytes,
+        }),
+    ))
+}
+
+/// Send an outbox entry. The Ack, or its absence, comes back through
+/// handle_response or handle_send_error.
+fn send_delivery(
+    state: &mut State,
+    transport: &mut dyn Transport,
+    job_id: &str,
+) -> anyhow::Result<()> {
+    let Some(delivery) = state.outbox.get(job_id) else {
+        return Ok(());
+    };
+    let coordinator = delivery.coordinator.clone();
+    let (body, blob) = report_request(state, &coordinator, &delivery.outcome)?;
+    let context = serde_json::to_vec(&PendingContext::Delivery { job_id: job_id.to_string() })?;
+    transport.send(&coordinator, body, blob, Some((DELIVERY_TIMEOUT_SECS, context)))?;
+    if let Some(delivery) = state.outbox.get_mut(job_id) {
+        delivery.attempts += 1;
+    }
+    save_state(transport, state)
+}
+
+/// The coordinator answered a report, so it no longer needs resending.
+fn delivery_settled(
+    state: &mut State,
+    transport: &mut dyn Transport,
+    job_id: &str,
+) -> anyhow::Result<()> {
+    if state.outbox.remove(job_id).is_some() {
+        state.journal.record(JournalEntry::Reported { job_id: job_id.to_string() });
+    }
+    save_state(transport, state)
+}
+
+/// Resend an unacknowledged report after a backoff, or drop it once it ran
+/// out of attempts.
+fn retry_delivery(
+    state: &mut State,
+    transport: &mut dyn Transport,
+    job_id: &str,
+    error: String,
+) -> anyhow::Result<()> {
+    let Some(delivery) = state.outbox.get_mut(job_id) else {
+        return Ok(());
+    };
+    kiprintln!("report on {} not acknowledged (attempt {}): {}", job_id, delivery.attempts, error);
+    delivery.last_error = Some(error);
+    if delivery.attempts >= MAX_DELIVERY_ATTEMPTS {
+        kiprintln!("giving up on reporting {} to {}", job_id, delivery.coordinator);
+        if state.outbox.abandon(job_id) {
+            state.journal.record(JournalEntry::Abandoned { job_id: job_id.to_string() });
+            state.mark_unpublished();
+        }
+        return save_state(transport, state);
     }
+    let delay = delivery.retry_delay_ms();
+    transport.set_timer(
+        delay,
+        serde_json::to_vec(&PendingContext::RetryDelivery { job_id: job_id.to_string() })?,
+    );
+    save_state(transport, state)
+}
 
+/// Report every queued job as failed so the coordinator can reschedule it.
+fn fail_queued_jobs(
+    state: &mut State,
+    transport: &mut dyn Transport,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let queued: Vec<Assignment> = state.job_queue.drain(..).collect();
+    for assignment in queued {
+        let outcome = match assignment {
+            Assignment::Single(work_request) => JobOutcome::Failed(WorkError {
+                id: work_request.id,
+                error: reason.to_string(),
+                kind: WorkErrorKind::Other,
+                timestamp,
+            }),
+            Assignment::Batch(batch) => JobOutcome::BatchCompleted(BatchResult {
+                batch_id: batch.id,
+                results: batch.items.into_iter()
+                    .map(|item| ItemResult {
+                        id: item.id,
+                        outcome: ItemOutcome::Failed { error: reason.to_string() },
+                    })
+                    .collect(),
+                timestamp,
+            }),
+        };
+        report_outcome(state, transport, outcome)?;
+    }
     Ok(())
 }
 
+/// Report the worker's output for the current job, or fail the job when
+/// the output did not pass validation.
+fn complete_work(
+    state: &mut State,
+    transport: &mut dyn Transport,
+    embeddings: Result<Vec<f32>, EmbeddingViolation>,
+) -> anyhow::Result<()> {
+    let ProviderState::Working { request, .. } = &state.state else {
+        return Ok(());
+    };
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+
+    let embeddings = match embeddings {
+        Ok(embeddings) => embeddings,
+        Err(violation) => {
+            // A corrupt result is worse than none, report it as a failure
+            kiprintln!("rejecting result for {}: {}", request.id, violation);
+            let work_error = WorkError {
+                id: request.id.clone(),
+                error: format!("invalid embedding: {}", violation),
+                kind: WorkErrorKind::InvalidEmbedding(violation),
+                timestamp,
+            };
+            return fail_work(state, transport, work_error);
+        }
+    };
+
+    let work_result = WorkResult {
+        id: request.id.clone(),
+        embeddings,
+        space: Some(EmbeddingSpace {
+            model: request.model.clone(),
+            modality: request.input().modality(),
+        }),
+        timestamp,
+    };
+
+    // Send result to coordinator
+    report_outcome(state, transport, JobOutcome::Completed(work_result.clone()))?;
+
+    state.safe_transition(transport, ProviderEvent::CompleteWork(work_result))
+}
+
+fn send_work_failed(
+    state: &mut State,
+    transport: &mut dyn Transport,
+    work_error: &WorkError,
+) -> anyhow::Result<()> {
+    kiprintln!("reporting failure of {}: {}", work_error.id, work_error.error);
+    report_outcome(state, transport, JobOutcome::Failed(work_error.clone()))
+}
+
+/// Report the current job as failed and move to Failed.
+fn fail_work(
+    state: &mut State,
+    transport: &mut dyn Transport,
+    work_error: WorkError,
+) -> anyhow::Result<()> {
+    send_work_failed(state, transport, &work_error)?;
+    state.safe_transition(transport, ProviderEvent::FailWork { error: work_error })
+}
+
+/// Report the batch being worked on, failing every item that has no result
+/// yet. Returns the error to fail the run with.
+fn settle_batch(
+    state: &mut State,
+    transport: &mut dyn Transport,
+    error: String,
+) -> anyhow::Result<Option<WorkError>> {
+    let ProviderState::WorkingBatch { batch, .. } = &state.state else {
+        return Ok(None);
+    };
+    let batch = batch.clone();
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let mut results = state.batch_run.take()
+        .map(|run| run.results)
+        .unwrap_or_default();
+    for item in &batch.items {
+        if !results.iter().any(|r| r.id == item.id) {
+            results.push(ItemResult {
+                id: item.id.clone(),
+                outcome: ItemOutcome::Failed { error: error.clone() },
+            });
+        }
+    }
+
+    report_outcome(state, transport, JobOutcome::BatchCompleted(BatchResult {
+        batch_id: batch.id.clone(),
+        results,
+        timestamp,
+    }))?;
+
+    Ok(Some(WorkError {
+        id: batch.id.clone(),
+        error,
+        kind: WorkErrorKind::Other,
+        timestamp,
+    }))
+}
+
+/// Give up on the current job after its worker disconnected and no other
+/// worker took it over within the grace period.
+fn fail_orphaned_job(state: &mut State, transport: &mut dyn Transport) -> anyhow::Result<()> {
+    let reason = "worker disconnected".to_string();
+    let work_error = match &state.state {
+        ProviderState::Working { request, .. } => {
+            let work_error = WorkError {
+                id: request.id.clone(),
+                error: reason,
+                kind: WorkErrorKind::Other,
+                timestamp: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)?
+                    .as_secs(),
+            };
+            send_work_failed(state, transport, &work_error)?;
+            work_error
+        }
+        ProviderState::WorkingBatch { .. } => match settle_batch(state, transport, reason)? {
+            Some(work_error) => work_error,
+            None => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+
+    state.safe_transition(transport, ProviderEvent::FailWork { error: work_error })
+}
+
+/// Fail the current job once its deadline passed and free the provider for
+/// the next one.
+fn time_out_job(
+    state: &mut State,
+    workers: &WorkerPool,
+    transport: &mut dyn Transport,
+) -> anyhow::Result<()> {
+    let Some(job_id) = state.current_job_id().map(str::to_string) else {
+        return Ok(());
+    };
+    let reason = "job timed out".to_string();
+    kiprintln!("{} ran past its deadline", job_id);
+
+    // Whatever the worker sends for it from now on is stale
+    if let Some(channel_id) = workers.holder(&job_id) {
+        push_to_channel(transport, channel_id, &ProviderPush::CancelWork { id: job_id.clone() })?;
+    }
+
+    let work_error = match &state.state {
+        ProviderState::Working { .. } => {
+            let work_error = WorkError {
+                id: job_id,
+                error: reason,
+                kind: WorkErrorKind::Other,
+                timestamp: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)?
+                    .as_secs(),
+            };
+            send_work_failed(state, transport, &work_error)?;
+            work_error
+        }
+        _ => match settle_batch(state, transport, reason)? {
+            Some(work_error) => work_error,
+            None => return Ok(()),
+        },
+    };
+
+    state.safe_transition(transport, ProviderEvent::TimeOut { error: work_error })
+}
+
+/// Validate one entry of a `batch_result` message from the UI.
+fn parse_item_result(report: &BatchItemReport, spec: Option<&ModelSpec>) -> ItemResult {
+    let outcome = match (&report.error, &report.embeddings) {
+        (Some(error), _) => ItemOutcome::Failed { error: error.clone() },
+        (None, Some(values)) => match validate_embeddings(values, spec) {
+            Ok(embeddings) => ItemOutcome::Completed { embeddings },
+            Err(violation) => ItemOutcome::Failed { error: format!("invalid embedding: {}", violation) },
+        },
+        (None, None) => ItemOutcome::Failed { error: "missing embeddings".to_string() },
+    };
+    ItemResult { id: report.id.clone(), outcome }
+}
+
+/// Merge a worker's batch results, resend failed items while they have
+/// attempts left, and report the batch once every item is settled.
+fn handle_batch_result(
+    state: &mut State,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+    batch_id: &str,
+    results: &[BatchItemReport],
+) -> anyhow::Result<Result<(), String>> {
+    let ProviderState::WorkingBatch { batch, .. } = &state.state else {
+        return Ok(Err("batch result while not working on a batch".to_string()));
+    };
+    let batch = batch.clone();
+    if batch_id != batch.id {
+        return Ok(Err("batch result for unknown batch".to_string()));
+    }
+    let spec = state.models.get(&batch.model);
+    let reported: Vec<ItemResult> = results.iter()
+        .map(|report| parse_item_result(report, spec))
+        .collect();
+
+    let run = state.batch_run.get_or_insert_with(|| BatchRun {
+        batch_id: batch.id.clone(),
+        results: Vec::new(),
+        attempt: 1,
+    });
+
+    let mut retry = Vec::new();
+    for item in &batch.items {
+        if run.results.iter().any(|r| r.id == item.id) {
+            continue;
+        }
+        let outcome = reported.iter()
+            .find(|r| r.id == item.id)
+            .map(|r| r.outcome.clone())
+            .unwrap_or(ItemOutcome::Failed { error: "no result from worker".to_string() });
+        match outcome {
+            ItemOutcome::Failed { .. } if run.attempt < MAX_BATCH_ITEM_ATTEMPTS => {
+                retry.push(item.clone());
+            }
+            outcome => run.results.push(ItemResult { id: item.id.clone(), outcome }),
+        }
+    }
+
+    if !retry.is_empty() {
+        run.attempt += 1;
+        kiprintln!("retrying {} failed items of batch {} (attempt {})", retry.len(), batch.id, run.attempt);
+        if let Some(channel_id) = workers.holder(&batch.id).or_else(|| workers.pick()) {
+            workers.assign(channel_id, &batch.id);
+            push_to_channel(transport, channel_id, &ProviderPush::batch(&batch, &retry))?;
+        }
+        save_state(transport, state)?;
+        return Ok(Ok(()));
+    }
+
+    let batch_result = BatchResult {
+        batch_id: batch.id.clone(),
+        results: run.results.clone(),
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+    };
+    report_outcome(state, transport, JobOutcome::BatchCompleted(batch_result.clone()))?;
+    state.safe_transition(transport, ProviderEvent::CompleteBatch(batch_result))?;
+    Ok(Ok(()))
+}
+
 fn handle_coordinator_message(
     state: &mut State,
-    channel_ids: &HashSet<u32>,
-    message: &Message,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+    source: &Address,
+    body: &[u8],
+    expects_response: bool,
 ) -> anyhow::Result<()> {
-    let request: ProviderRequest = serde_json::from_slice(message.body())?;
+    if !state.accepts_requests_from(source) {
+        kiprintln!("rejecting request from {}: not our coordinator", source);
+        if expects_response {
+            transport.respond(serde_json::to_vec(&ProviderResponse::Error(
+                "not bound to this coordinator".to_string(),
+            ))?)?;
+        }
+        return Ok(());
+    }
+    let request: ProviderRequest = serde_json::from_slice(body)?;
     
     match request {
         ProviderRequest::AssignWork(work_request) => {
             kiprintln!("assigned work");
-            handle_work_request(state, channel_ids, work_request)?;
+            handle_work_request(state, workers, transport, Assignment::Single(work_request))?;
+        }
+        ProviderRequest::AssignBatch(batch) => {
+            kiprintln!("assigned batch of {} items", batch.items.len());
+            handle_work_request(state, workers, transport, Assignment::Batch(batch))?;
         }
         ProviderRequest::HealthPing => {
-            // TODO: send back health statistics
-            Response::new()
-                .body(serde_json::to_vec(&ProviderResponse::HealthPong)?)
-                .send()?;
+            let response = if state.protocol_version >= protocol::MIN_HEALTH_REPORT_VERSION {
+                ProviderResponse::HealthReport(state.health.report(
+                    &state.state,
+                    workers.len(),
+                    &state.models.ids(),
+                ))
+            } else {
+                ProviderResponse::HealthPong
+            };
+            transport.respond(serde_json::to_vec(&response)?)?;
         }
         ProviderRequest::Kick => {
             kiprintln!("memento mori");
-            state.transition(ProviderEvent::Kicked)?;
-            for &channel_id in channel_ids {
-                state.safe_transition(ProviderEvent::Kicked, channel_id)?;
-            }
+            state.safe_transition(transport, ProviderEvent::Kicked)?;
         }
     }
     Ok(())
 }
 
+fn holds_current_job(state: &State, workers: &WorkerPool, channel_id: u32) -> bool {
+    state.current_job_id()
+        .is_some_and(|job_id| workers.holder(job_id) == Some(channel_id))
+}
+
+/// Act on a message from a UI tab. The inner error says why the message
+/// itself is invalid, the outer one that handling it failed.
 fn handle_websocket_message(
     state: &mut State,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
     channel_id: u32,
-    message: WebSocketMessage,
-) -> anyhow::Result<()> {
-    match message.message_type.as_str() {
-        "work_result" => {
-            if let Some(embeddings) = message.data.as_array() {
-                let embeddings = embeddings.iter()
-                    .map(|v| v.as_f64().unwrap_or(0.0) as f32)
-                    .collect();
-
-                if let ProviderState::Working { request, .. } = &state.state {
-                    let work_result = WorkResult {
-                        id: request.id.clone(),
-                        embeddings,
-                        timestamp: std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)?
-                            .as_secs(),
-                    };
-
-                    // Send result to coordinator
-                    if let Some(coordinator) = &state.coordinator {
-                        let res = Request::new()
-                            .target(coordinator)
-                            .body(serde_json::to_vec(&ProviderResponse::WorkCompleted {
-                                result: work_result.clone(),
-                            })?)
-                            .send()?;
-                        match res {
-                            _ => kiprintln!("work_result received by coordinator"),
-                        }
-                    }
+    message: UiMessage,
+) -> anyhow::Result<Result<(), String>> {
+    // Only the channel a job was sent to may report on it
+    if message.reports_on_job() && !holds_current_job(state, workers, channel_id) {
+        return Ok(Err(format!(
+            "channel {} sent {} for a job it does not hold",
+            channel_id, message.name()
+        )));
+    }
 
-                    state.safe_transition(ProviderEvent::CompleteWork(work_result), channel_id)?;
-                }
+    match message {
+        UiMessage::BatchResult { batch_id, results } => {
+            if let Err(reason) = handle_batch_result(state, workers, transport, &batch_id, &results)? {
+                return Ok(Err(reason));
+            }
+        }
+        UiMessage::ChallengeResult { challenge_id, model, embeddings } => {
+            if let Err(reason) = handle_challenge_result(state, workers, transport, &challenge_id, &model, &embeddings)? {
+                return Ok(Err(reason));
+            }
+        }
+        UiMessage::ChallengeFailed { error } => {
+            if let Some(pending) = state.pending_registration.take() {
+                let error = error.unwrap_or_else(|| "Unknown error".to_string());
+                kiprintln!("could not answer challenge {}: {}", pending.challenge_id, error);
+                save_state(transport, state)?;
+                notify_registration_result(workers, transport, &RegistrationResult::error(
+                    format!("model challenge failed: {}", error),
+                ))?;
+            }
+        }
+        UiMessage::WorkResult(values) => {
+            if let ProviderState::Working { request, .. } = &state.state {
+                let embeddings = validate_embeddings(&values, state.models.get(&request.model));
+                complete_work(state, transport, embeddings)?;
             }
         }
-        "work_failed" => {
+        UiMessage::WorkFailed { error } => {
+            let error = error.unwrap_or_else(|| "Unknown error".to_string());
             if let ProviderState::Working { request, .. } = &state.state {
                 kiprintln!("Work failed...");
-                let error = message.data["error"].as_str()
-                    .unwrap_or("Unknown error")
-                    .to_string();
-
                 let work_error = WorkError {
                     id: request.id.clone(),
                     error,
+                    kind: WorkErrorKind::Other,
                     timestamp: std::time::SystemTime::now()
                         .duration_since(std::time::UNIX_EPOCH)?
                         .as_secs(),
                 };
 
-                if let Some(coordinator) = &state.coordinator {
-                    let res = Request::new()
-                        .target(coordinator)
-                        .body(serde_json::to_vec(&ProviderResponse::WorkFailed {
-                            error: work_error.clone(),
-                        })?)
-                        .send()?;
-                    match res {
-                        _ => kiprintln!("work_failed received by coordinator"),
-                    }
+                fail_work(state, transport, work_error)?;
+            } else {
+                // The worker gave up on the whole batch, settle every item still open
+                if let Some(work_error) = settle_batch(state, transport, error)? {
+                    state.safe_transition(transport, ProviderEvent::FailWork { error: work_error })?;
                 }
-
-                state.safe_transition(ProviderEvent::FailWork {
-                    error: work_error.clone(),
-                }, channel_id)?;
             }
         }
-        "still_bound" => {
+        UiMessage::StillBound => {
             let request = CoordinatorRequest::ProviderReady;
             if let Some(coordinator) = &state.coordinator {
                 let response: CoordinatorResponse = serde_json::from_slice(
-                    Request::to(coordinator)
-                        .body(serde_json::to_vec(&request)?)
-                        .send_and_await_response(5)??
-                        .body()
+                    &transport.call(coordinator, serde_json::to_vec(&request)?, 5)?
+                        .map_err(|kind| anyhow::anyhow!("coordinator unreachable: {:?}", kind))?
                 )?;  
 
                 match response {
                     CoordinatorResponse::Nack => {
                         kiprintln!("You are not bound");
-                        state.safe_transition(ProviderEvent::Kicked, channel_id)?;
+                        state.safe_transition(transport, ProviderEvent::Kicked)?;
                     }
                     CoordinatorResponse::Ack => {
                         kiprintln!("coordinator acknowledged that we are still bound");
-                        state.safe_transition(ProviderEvent::GoOnline(coordinator.clone()), channel_id)?;
+                        state.safe_transition(transport, ProviderEvent::GoOnline(coordinator.clone()))?;
                     }
                     _ => {
                         kiprintln!("coordinator did not acknowledge that we are bound");
-                        state.safe_transition(ProviderEvent::Kicked, channel_id)?;
+                        state.safe_transition(transport, ProviderEvent::Kicked)?;
                     }
                 }
             } else {
                 kiprintln!("no coordinator, going offline.");
-                state.safe_transition(ProviderEvent::Kicked, channel_id)?;
+                state.safe_transition(transport, ProviderEvent::Kicked)?;
             }
 
         }
-        "go_offline" => {
-            if let Some(coordinator) = &state.coordinator {
-                let response: CoordinatorResponse = serde_json::from_slice(
-                    Request::to(coordinator)
-                        .body(serde_json::to_vec(&CoordinatorRequest::GoOffline)?)
-                        .send_and_await_response(4)??
-                        .body()
-                )?;
+        UiMessage::GoOffline => {
+            if !state.allows(&ProviderEvent::GoOffline) {
+                return Ok(Err("cannot go offline from the current state".to_string()));
+            }
+            go_offline(state, transport)?;
+        }
+        UiMessage::ModelStatus { model, loaded, warm } => {
+            state.health.set_model_status(ModelStatus { model, loaded, warm });
+        }
+        UiMessage::WorkerCapabilities { webgpu } => {
+            kiprintln!("worker {} webgpu: {}", channel_id, webgpu);
+            workers.set_webgpu(channel_id, webgpu);
+        }
+        UiMessage::ProgressUpdate { progress } => { //шит?
+            kiprintln!("progress_update");
+            // Only whole steps are journaled, a resumed job restarts from
+            // the last one anyway
+            let (job_id, previous) = match &state.state {
+                ProviderState::Working { request, progress } => (Some(request.id.clone()), *progress),
+                ProviderState::WorkingBatch { batch, progress } => (Some(batch.id.clone()), *progress),
+                _ => (None, None),
+            };
+            let step = |progress: u32| progress / journal::PROGRESS_STEP;
+            if let Some(job_id) = job_id.filter(|_| previous.map(step) != Some(step(progress))) {
+                state.journal.record(JournalEntry::Progress { job_id, progress });
+            }
+            state.safe_transition(transport, ProviderEvent::UpdateProgress(progress))?;
+        }
+    }
 
-                match response {
-                    CoordinatorResponse::Ack => {
-                        kiprintln!("coordinator acknowledged that we are offline");
-                        state.safe_transition(ProviderEvent::GoOffline, channel_id)?;
-                    }
-                    _ => {
-                        kiprintln!("coordinator did not acknowledge that we are offline");
-                        state.safe_transition(ProviderEvent::GoOffline, channel_id)?;
-                    }
+    dispatch_next_job(state, workers, transport)?;
+    save_state(transport, state)?;
+    Ok(Ok(()))
+}
+
+/// Tell a channel why its push was rejected, then fail with the same error.
+/// Only for pushes that are invalid, a handler that fails is not the
+/// channel's fault and is just returned.
+fn reject_push(
+    transport: &mut dyn Transport,
+    channel_id: u32,
+    message_type: Option<&str>,
+    error: anyhow::Error,
+) -> anyhow::Result<()> {
+    push_to_channel(transport, channel_id, &ProviderPush::Error {
+        message: error.to_string(),
+        message_type: message_type.map(str::to_string),
+    })?;
+    Err(error)
+}
+
+fn send_json_response(
+    transport: &mut dyn Transport,
+    status: http::StatusCode,
+    body: &serde_json::Value,
+) -> anyhow::Result<()> {
+    transport.http_respond(status, serde_json::to_vec(body)?)
+}
+
+fn send_json_error(
+    transport: &mut dyn Transport,
+    status: http::StatusCode,
+    message: &str,
+) -> anyhow::Result<()> {
+    send_json_response(transport, status, &serde_json::json!({
+        "status": "error",
+        "message": message
+    }))
+}
+
+/// Parse and validate the /register_provider body into a coordinator address
+/// and the models we will offer it.
+fn parse_register_request(
+    state: &State,
+    body: Option<&[u8]>,
+) -> Result<(Address, Vec<String>), (http::StatusCode, String)> {
+    let Some(body) = body else {
+        return Err((http::StatusCode::BAD_REQUEST, "missing request body".to_string()));
+    };
+
+    let register_request: RegisterRequest = serde_json::from_slice(body)
+        .map_err(|e| (http::StatusCode::BAD_REQUEST, format!("invalid request body: {e}")))?;
+
+    let coordinator = Address::from_str(register_request.coordinator_address.trim())
+        .map_err(|e| (
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "invalid coordinator address {:?}: {e}",
+                register_request.coordinator_address
+            ),
+        ))?;
+
+    let models = match register_request.supported_models {
+        Some(models) if !models.is_empty() => models,
+        _ => state.models.ids(),
+    };
+
+    let unsupported: Vec<&String> = models.iter()
+        .filter(|model| !state.models.ids().contains(model))
+        .collect();
+    if !unsupported.is_empty() {
+        return Err((
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            format!("unsupported models requested: {:?}", unsupported),
+        ));
+    }
+
+    Ok((coordinator, models))
+}
+
+/// Forward the coordinator's challenge inputs to the UI and remember them
+/// until every model has been answered.
+fn start_challenge(
+    state: &mut State,
+    workers: &WorkerPool,
+    transport: &mut dyn Transport,
+    coordinator: Address,
+    challenge_id: String,
+    inputs: Vec<ChallengeInput>,
+) -> anyhow::Result<RegistrationResult> {
+    kiprintln!("coordinator {} sent challenge {} for {} models", coordinator, challenge_id, inputs.len());
+
+    for input in &inputs {
+        let challenge_message = ProviderPush::ChallengeRequest {
+            challenge_id: challenge_id.clone(),
+            model: input.model.clone(),
+            uri: input.uri.clone(),
+        };
+        for channel_id in workers.channel_ids() {
+            push_to_channel(transport, channel_id, &challenge_message)?;
+        }
+    }
+
+    state.pending_registration = Some(PendingRegistration {
+        coordinator: coordinator.clone(),
+        challenge_id,
+        inputs,
+        outputs: Vec::new(),
+    });
+    save_state(transport, state)?;
+
+    Ok(RegistrationResult::pending(&coordinator, "verifying models with coordinator"))
+}
+
+/// Apply the coordinator's final answer to a registration attempt and return
+/// the JSON reported back to the UI, or the HTTP status and message when the
+/// coordinator did not register us.
+fn finish_registration(
+    state: &mut State,
+    transport: &mut dyn Transport,
+    coordinator: &Address,
+    response: &[u8],
+) -> anyhow::Result<Result<RegistrationResult, (http::StatusCode, String)>> {
+    // Whatever the answer, this attempt is over
+    state.pending_registration = None;
+    let response = match serde_json::from_slice(response) {
+        Ok(response) => response,
+        Err(e) => {
+            kiprintln!("unreadable registration answer from {}: {e}", coordinator);
+            save_state(transport, state)?;
+            return Ok(Err((
+                http::StatusCode::BAD_GATEWAY,
+                format!("coordinator {} sent an unreadable answer", coordinator),
+            )));
+        }
+    };
+    match response {
+        CoordinatorResponse::ProviderRegistered { required_models, protocol_version, encoding } => {
+            let protocol_version = match protocol::negotiate_version(protocol_version) {
+                Ok(version) => version,
+                Err(reason) => {
+                    kiprintln!("coordinator {} is incompatible: {}", coordinator, reason);
+                    save_state(transport, state)?;
+                    return Ok(Err((http::StatusCode::BAD_GATEWAY, reason)));
                 }
-            } else {
-                kiprintln!("no coordinator, going offline");
-                state.safe_transition(ProviderEvent::GoOffline, channel_id)?;
+            };
+            kiprintln!("Registration successful! (protocol v{})", protocol_version);
+            state.protocol_version = protocol_version;
+            // Only trust an encoding we actually offered
+            state.embedding_encoding = encoding
+                .filter(|e| state.settings.embedding_encodings.contains(e));
+            state.coordinators.upsert(coordinator.clone(), required_models.clone());
+            state.coordinators.mark_seen(coordinator, None);
+
+            // Persist the coordinator binding even when no UI channel is open
+            if let Err(e) = state.safe_transition(
+                transport,
+                ProviderEvent::RegisterWithCoordinator(coordinator.clone()),
+            ) {
+                kiprintln!("registration with {} left the state alone: {e}", coordinator);
             }
+
+            Ok(Ok(RegistrationResult::success(coordinator, required_models)))
         }
-        "progress_update" => { //шит?
-            if let Some(progress) = message.data["progress"].as_u64() {
-                kiprintln!("progress_update");
-                state.safe_transition(ProviderEvent::UpdateProgress(progress as u32), channel_id)?;
-            }
+        CoordinatorResponse::RegistrationRejected { reason } => {
+            // A refusal is the coordinator's answer, not a fault of ours, so
+            // the provider stays where it was
+            kiprintln!("Registration rejected. Reason: {:#?}", reason);
+            save_state(transport, state)?;
+            Ok(Err((http::StatusCode::FORBIDDEN, reason)))
+        }
+        other => {
+            // Ack and Nack used to count as registered, which let providers
+            // skip the model check. Only ProviderRegistered binds us now.
+            kiprintln!("coordinator did not complete the registration handshake: {:?}", other);
+            save_state(transport, state)?;
+            Ok(Err((
+                http::StatusCode::BAD_GATEWAY,
+                "coordinator did not complete the registration handshake".to_string(),
+            )))
         }
-        _ => println!("Unknown WebSocket message type: {}", message.message_type),
     }
+}
 
-    save_state(state)?;
+/// Record one challenge answer from the UI and, once every model is covered,
+/// send the outputs to the coordinator.
+fn handle_challenge_result(
+    state: &mut State,
+    workers: &WorkerPool,
+    transport: &mut dyn Transport,
+    challenge_id: &str,
+    model: &str,
+    embeddings: &[f64],
+) -> anyhow::Result<Result<(), String>> {
+    let Some(pending) = state.pending_registration.as_mut() else {
+        return Ok(Err("challenge result without a pending registration".to_string()));
+    };
+    if challenge_id != pending.challenge_id {
+        return Ok(Err("challenge result for unknown challenge".to_string()));
+    }
+    if !pending.inputs.iter().any(|input| input.model == model) {
+        return Ok(Err(format!("challenge result for unchallenged model {}", model)));
+    }
+    if pending.outputs.iter().any(|output| output.model == model) {
+        // Another tab already answered
+        return Ok(Ok(()));
+    }
+    pending.outputs.push(ChallengeOutput {
+        model: model.to_string(),
+        embeddings: embeddings.iter().map(|v| *v as f32).collect(),
+    });
+    if !pending.is_complete() {
+        save_state(transport, state)?;
+        return Ok(Ok(()));
+    }
+
+    let Some(pending) = state.pending_registration.take() else {
+        return Ok(Ok(()));
+    };
+    kiprintln!("answering challenge {} from {}", pending.challenge_id, pending.coordinator);
+    let body = serde_json::to_vec(&CoordinatorRequest::ChallengeResponse {
+        challenge_id: pending.challenge_id,
+        outputs: pending.outputs,
+    })?;
+    let response_data = match transport.call(&pending.coordinator, body, 30)? {
+        Ok(response) => match finish_registration(state, transport, &pending.coordinator, &response)? {
+            Ok(response_data) => response_data,
+            Err((_, message)) => RegistrationResult::error(message),
+        },
+        Err(kind) => {
+            kiprintln!("coordinator {} unreachable: {:?}", pending.coordinator, kind);
+            save_state(transport, state)?;
+            RegistrationResult::error(format!("coordinator {} unreachable", pending.coordinator))
+        }
+    };
+
+    notify_registration_result(workers, transport, &response_data)?;
+    Ok(Ok(()))
+}
+
+fn notify_registration_result(
+    workers: &WorkerPool,
+    transport: &mut dyn Transport,
+    response_data: &RegistrationResult,
+) -> anyhow::Result<()> {
+    let message = ProviderPush::RegistrationResult(response_data.clone());
+    for channel_id in workers.channel_ids() {
+        push_to_channel(transport, channel_id, &message)?;
+    }
     Ok(())
 }
 
 fn handle_http_request(
     state: &mut State,
-    channel_ids: &HashSet<u32>,
-    req: http::server::IncomingHttpRequest,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+    req: HttpRequest,
 ) -> anyhow::Result<()> {
-    match req.path()?.as_str() {
+    match req.path.as_str() {
         "/register_provider" => {
-            let Some(_blob) = get_blob() else { 
-                return Err(anyhow::anyhow!("missing request body")) 
+            let (coordinator, models) = match parse_register_request(state, req.body.as_deref()) {
+                Ok(parsed) => parsed,
+                Err((status, message)) => {
+                    kiprintln!("rejecting register_provider request: {}", message);
+                    return send_json_error(transport, status, &message);
+                }
             };
-
-            //let register_request: RegisterRequest = serde_json::from_slice(&blob.bytes)?;
-            //let coordinator = kinode_process_lib::Address::from_str(&register_request.coordinator_address)?;
-
-            // Send Request to coordinator and await Response
-            let coordinator = Address::from_str("pertinent.os@coordinator:coordinator:haeceity.os")?;
+            if !state.settings.allows_coordinator(&coordinator) {
+                kiprintln!("refusing to register with {}: not in the coordinator allowlist", coordinator);
+                return send_json_error(
+                    transport,
+                    http::StatusCode::FORBIDDEN,
+                    &format!("coordinator {} is not in the allowlist", coordinator),
+                );
+            }
+            if !state.allows(&ProviderEvent::RegisterWithCoordinator(coordinator.clone())) {
+                return send_json_error(
+                    transport,
+                    http::StatusCode::CONFLICT,
+                    "finish the current job before registering with a coordinator",
+                );
+            }
             kiprintln!("trying to register under coordinator: {:?}", coordinator);
 
-            let provider_event: ProviderEvent;
-
             // Send registration request to coordinator
-            let response = Request::new()
-                .target(coordinator.clone())
-                .body(serde_json::to_vec(&CoordinatorRequest::RegisterProvider {
-                    supported_models: state.supported_models.clone(),
-                })?)
-                .send_and_await_response(30)??;
-
-            let response_data = match serde_json::from_slice(response.body())? {
-                CoordinatorResponse::ProviderRegistered { required_models } => {
-                    kiprintln!("Registration successful!");
-                    provider_event = ProviderEvent::RegisterWithCoordinator(coordinator.clone());
-                    
-                    serde_json::json!({
-                        "status": "success",
-                        "required_models": required_models
-                    })
-                }
-                CoordinatorResponse::RegistrationRejected { reason } => {
-                    kiprintln!("Registration rejected. Reason: {:#?}", reason);
-                    provider_event = ProviderEvent::Error(reason.clone());
-                    
-                    serde_json::json!({
-                        "status": "error",
-                        "message": reason
-                    })
+            let body = serde_json::to_vec(&CoordinatorRequest::RegisterProvider {
+                capabilities: state.models.capabilities().into_iter()
+                    .filter(|m| models.contains(&m.model))
+                    .collect(),
+                models: state.models.list().iter()
+                    .filter(|m| models.contains(&m.id))
+                    .cloned()
+                    .collect(),
+                supported_models: models,
+                protocol_version: protocol::PROTOCOL_VERSION,
+                encodings: state.settings.embedding_encodings.clone(),
+            })?;
+            let response = match transport.call(&coordinator, body, 30)? {
+                Ok(response) => response,
+                Err(kind) => {
+                    kiprintln!("coordinator {} unreachable: {:?}", coordinator, kind);
+                    return send_json_error(
+                        transport,
+                        http::StatusCode::BAD_GATEWAY,
+                        &format!("coordinator {} unreachable", coordinator),
+                    );
                 }
-                CoordinatorResponse::Ack => {
-                    kiprintln!("Already in network");
-                    // this could allow providers to bypass the model check. TODO: Create handshake protocol
-                    provider_event = ProviderEvent::RegisterWithCoordinator(coordinator.clone());
+            };
 
-                    serde_json::json!({
-                        "status": "success",
-                    })
-                }
-                CoordinatorResponse::Nack => {
-                    provider_event = ProviderEvent::RegisterWithCoordinator(coordinator.clone());
-                    
-                    serde_json::json!({
-                        "status": "error",
-                        "message": "Nack received"
-                    })
+            let response_data = match serde_json::from_slice(&response) {
+                Ok(CoordinatorResponse::Challenge { challenge_id, inputs }) => {
+                    if !workers.has_capable() {
+                        return send_json_error(
+                            transport,
+                            http::StatusCode::CONFLICT,
+                            "open the provider UI to answer the coordinator's model challenge",
+                        );
+                    }
+                    start_challenge(state, workers, transport, coordinator, challenge_id, inputs)?
                 }
-                _ => return Err(anyhow::anyhow!("unexpected coordinator response")),
+                _ => match finish_registration(state, transport, &coordinator, &response)? {
+                    Ok(response_data) => response_data,
+                    Err((status, message)) => return send_json_error(transport, status, &message),
+                },
             };
 
+            send_json_response(transport, http::StatusCode::OK, &serde_json::to_value(&response_data)?)?;
+        }
+        "/coordinators" => {
+            let query = &req.query;
+            // Discovery blocks on every known coordinator, so it never runs
+            // inside the request. A forced refresh shows up on the next one.
+            if query.get("refresh").is_some_and(|v| v == "true") {
+                transport.set_timer(
+                    0,
+                    serde_json::to_vec(&PendingContext::RefreshCoordinators { periodic: false })?,
+                );
+            }
+
+            let coordinators = state.coordinators.list(query.get("model").map(String::as_str));
+            send_json_response(transport, http::StatusCode::OK, &serde_json::to_value(coordinators)?)?;
+        }
+        "/settings" => {
+            // An empty body just reads the current settings
+            if let Some(body) = req.body.as_deref().filter(|body| !body.is_empty()) {
+                let update: SettingsUpdate = match serde_json::from_slice(body) {
+                    Ok(update) => update,
+                    Err(e) => return send_json_error(
+                        transport,
+                        http::StatusCode::BAD_REQUEST,
+                        &format!("invalid request body: {e}"),
+                    ),
+                };
+                if let Err(reason) = update.validate() {
+                    return send_json_error(transport, http::StatusCode::UNPROCESSABLE_ENTITY, &reason);
+                }
+                update.apply(&mut state.settings);
+                save_state(transport, state)?;
+            }
 
-            http::server::send_response(
+            send_json_response(transport, http::StatusCode::OK, &serde_json::to_value(&state.settings)?)?;
+        }
+        "/workers" => {
+            send_json_response(transport, http::StatusCode::OK, &serde_json::to_value(workers.list())?)?;
+        }
+        "/transitions" => {
+            // Newest last, ?rejected=true for just the refused ones
+            let query = &req.query;
+            let limit = query.get("limit")
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(MAX_AUDIT_ENTRIES);
+            let rejected_only = query.get("rejected").is_some_and(|v| v == "true");
+            send_json_response(
+                transport,
                 http::StatusCode::OK,
-                Some(std::collections::HashMap::from([(
-                    String::from("Content-Type"),
-                    String::from("application/json"),
-                )])),
-                serde_json::to_vec(&response_data)?,
-            );
+                &serde_json::to_value(state.audit.recent(limit, rejected_only))?,
+            )?;
+        }
+        "/schema" => {
+            send_json_response(transport, http::StatusCode::OK, &ui_messages::schema())?;
+        }
+        "/models" => {
+            // Changes reach the coordinator with the next registration
+            match req.method {
+                http::Method::GET => {}
+                http::Method::POST | http::Method::PUT => {
+                    let Some(body) = req.body.as_deref() else {
+                        return send_json_error(transport, http::StatusCode::BAD_REQUEST, "missing request body");
+                    };
+                    let spec: ModelSpec = match serde_json::from_slice(body) {
+                        Ok(spec) => spec,
+                        Err(e) => return send_json_error(
+                            transport,
+                            http::StatusCode::BAD_REQUEST,
+                            &format!("invalid model: {e}"),
+                        ),
+                    };
+                    if let Err(reason) = state.models.upsert(spec) {
+                        return send_json_error(transport, http::StatusCode::UNPROCESSABLE_ENTITY, &reason);
+                    }
+                    save_state(transport, state)?;
+                }
+                http::Method::DELETE => {
+                    let Some(id) = req.query.get("id") else {
+                        return send_json_error(transport, http::StatusCode::BAD_REQUEST, "missing id parameter");
+                    };
+                    if state.models.remove(id).is_none() {
+                        return send_json_error(
+                            transport,
+                            http::StatusCode::NOT_FOUND,
+                            &format!("unknown model {}", id),
+                        );
+                    }
+                    save_state(transport, state)?;
+                }
+                _ => return send_json_error(transport, http::StatusCode::METHOD_NOT_ALLOWED, "unsupported method"),
+            }
+
+            send_json_response(transport, http::StatusCode::OK, &serde_json::to_value(state.models.list())?)?;
+        }
+        _ => return Err(anyhow::anyhow!("unknown endpoint")),
+    }
+
+    Ok(())
+}
 
-            for &channel_id in channel_ids {
-                state.safe_transition(provider_event.clone(), channel_id)?;
+fn handle_websocket_open(
+    state: &mut State,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+    channel_id: u32,
+) -> anyhow::Result<()> {
+    let session_id = workers.connect(channel_id);
+    // The tab introduces itself once it knows its session
+    push_to_channel(transport, channel_id, &ProviderPush::Session { session_id, channel_id })?;
+    notify_ui_state_change(transport, state, &channel_id)?;
+    // Possibly the tab that left with the current job coming back
+    if state.current_job_id().is_some_and(|job_id| workers.holder(job_id).is_none()) {
+        reassign_job(state, workers, transport)?;
+    }
+    // A worker may be what a queued job was waiting for
+    dispatch_next_job(state, workers, transport)
+}
+
+fn handle_websocket_close(
+    state: &mut State,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+    channel_id: u32,
+) -> anyhow::Result<()> {
+    if let Some(worker) = workers.disconnect(channel_id) {
+        if workers.is_empty() {
+            state.health.clear_models();
+        }
+        if let WorkerStatus::Busy { job_id } = worker.status {
+            if state.current_job_id() == Some(job_id.as_str()) && !reassign_job(state, workers, transport)? {
+                kiprintln!("worker {} left with {}, waiting {}ms for a worker", channel_id, job_id, RECONNECT_GRACE_MS);
+                transport.set_timer(
+                    RECONNECT_GRACE_MS,
+                    serde_json::to_vec(&PendingContext::OrphanedJob { job_id })?,
+                );
             }
         }
-        "/coordinators" => {
-            // TODO: Hardcoded coordinator list for now, but should be something else
-            let coordinators = serde_json::json!([{
-                "address": "pertinent.os",
-                "requiredModels": ["clip-vit-base-patch16"],
-            }]);
+    }
+    Ok(())
+}
 
-            http::server::send_response(
-                http::StatusCode::OK,
-                Some(std::collections::HashMap::from([(
-                    String::from("Content-Type"),
-                    String::from("application/json"),
-                )])),
-                serde_json::to_vec(&coordinators)?,
-            );
+fn handle_websocket_push(
+    state: &mut State,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+    channel_id: u32,
+    message_type: WsMessageType,
+    bytes: Option<Vec<u8>>,
+) -> anyhow::Result<()> {
+    if !workers.contains(channel_id) {
+        return Err(anyhow::anyhow!("received push from unknown channel"));
+    }
+
+    // Binary frames are always a work result, see decode_embedding_frame
+    if let (WsMessageType::Binary, Some(bytes)) = (&message_type, &bytes) {
+        if !holds_current_job(state, workers, channel_id) {
+            return reject_push(transport, channel_id, Some("work_result"), anyhow::anyhow!(
+                "channel {} sent a result for a job it does not hold", channel_id
+            ));
         }
-        _ => return Err(anyhow::anyhow!("unknown endpoint")),
+        if let ProviderState::Working { request, .. } = &state.state {
+            let embeddings = decode_embedding_frame(bytes, state.models.get(&request.model));
+            complete_work(state, transport, embeddings)?;
+            dispatch_next_job(state, workers, transport)?;
+            save_state(transport, state)?;
+        }
+        return Ok(());
     }
 
+    let Some(bytes) = bytes else {
+        return Ok(());
+    };
+    let ws_message: WebSocketMessage = match serde_json::from_slice(&bytes) {
+        Ok(ws_message) => ws_message,
+        Err(e) => return reject_push(transport, channel_id, None, anyhow::anyhow!("invalid message: {e}")),
+    };
+    let message_type = ws_message.message.name();
+    if !workers.session_matches(channel_id, ws_message.session_id.as_deref()) {
+        return reject_push(transport, channel_id, Some(message_type), anyhow::anyhow!(
+            "push on channel {} without its session", channel_id
+        ));
+    }
+    match handle_websocket_message(state, workers, transport, channel_id, ws_message.message)? {
+        Ok(()) => Ok(()),
+        Err(reason) => reject_push(transport, channel_id, Some(message_type), anyhow::anyhow!(reason)),
+    }
+}
+
+/// Set the timer for the next periodic discovery round.
+fn schedule_coordinator_refresh(state: &State, transport: &mut dyn Transport) -> anyhow::Result<()> {
+    transport.set_timer(
+        state.coordinators.next_refresh_ms(),
+        serde_json::to_vec(&PendingContext::RefreshCoordinators { periodic: true })?,
+    );
     Ok(())
 }
 
-fn handle_http_server_message(
+/// Requests of ours that got no response in time.
+fn handle_send_error(
     state: &mut State,
-    channel_ids: &mut HashSet<u32>,
-    message: &Message,
+    transport: &mut dyn Transport,
+    kind: &SendErrorKind,
+    context: Option<&[u8]>,
 ) -> anyhow::Result<()> {
-    match serde_json::from_slice(message.body())? {
-        HttpServerRequest::Http(req) => handle_http_request(state, channel_ids, req),
-        HttpServerRequest::WebSocketOpen { channel_id, .. } => {
-            channel_ids.insert(channel_id);
-            Ok(())
-        }
-        HttpServerRequest::WebSocketClose(channel_id) => {
-            if channel_ids.remove(&channel_id) {
-                // Only notify if we actually removed a channel
-                //notify_ui_state_change(state, channel_ids)?;
+    let Some(context) = context else {
+        return Ok(());
+    };
+
+    match serde_json::from_slice(context)? {
+        PendingContext::Delivery { job_id } => {
+            retry_delivery(state, transport, &job_id, format!("{:?}", kind))?;
+        }
+        PendingContext::Peers { coordinator, .. } => {
+            state.coordinators.peers_missing(&coordinator);
+            save_state(transport, state)?;
+        }
+        other => kiprintln!("send error for {:?}: {:?}", other, kind),
+    }
+    Ok(())
+}
+
+/// Responses we did not wait on: our timers firing and report Acks.
+fn handle_response(
+    state: &mut State,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
+    source: &Address,
+    body: &[u8],
+    context: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    let Some(context) = context else {
+        return Err(anyhow::anyhow!("response without context from {}", source));
+    };
+
+    match serde_json::from_slice(context)? {
+        PendingContext::OrphanedJob { job_id } => {
+            // It may have finished or found a worker in the meantime
+            if state.current_job_id() != Some(job_id.as_str()) || workers.holder(&job_id).is_some() {
+                return Ok(());
+            }
+            if !reassign_job(state, workers, transport)? {
+                kiprintln!("no worker took over {} within {}ms", job_id, RECONNECT_GRACE_MS);
+                fail_orphaned_job(state, transport)?;
             }
-            Ok(())
         }
-        HttpServerRequest::WebSocketPush { channel_id, .. } => {
-            if !channel_ids.contains(&channel_id) {
-                return Err(anyhow::anyhow!("received push from unknown channel"));
+        PendingContext::JobDeadline { job_id } => {
+            // Timers cannot be cancelled, ignore those of finished jobs
+            if state.current_job_id() == Some(job_id.as_str()) {
+                time_out_job(state, workers, transport)?;
             }
-            
-            if let Some(blob) = get_blob() {
-                let ws_message: WebSocketMessage = serde_json::from_slice(&blob.bytes)?;
-                handle_websocket_message(state, channel_id, ws_message)?;
+        }
+        PendingContext::Recover => recover(state, workers, transport)?,
+        PendingContext::Delivery { job_id } => {
+            match serde_json::from_slice(body) {
+                Ok(CoordinatorResponse::Ack) => delivery_settled(state, transport, &job_id)?,
+                Ok(CoordinatorResponse::Nack) => {
+                    kiprintln!("coordinator refused our report on {}", job_id);
+                    delivery_settled(state, transport, &job_id)?;
+                }
+                _ => retry_delivery(state, transport, &job_id, "unexpected answer".to_string())?,
+            }
+            // Pending deliveries are part of what the UI shows
+            state.mark_unpublished();
+        }
+        PendingContext::RetryDelivery { job_id } => send_delivery(state, transport, &job_id)?,
+        PendingContext::RefreshCoordinators { periodic } => {
+            let asked = state.coordinators.refresh(transport)?;
+            kiprintln!("asking {} coordinators for their peers", asked);
+            if periodic {
+                schedule_coordinator_refresh(state, transport)?;
+            }
+        }
+        PendingContext::Peers { coordinator, sent_at_ms } => {
+            let discovered = state.coordinators.peers_answered(&coordinator, body, sent_at_ms);
+            if discovered > 0 {
+                kiprintln!("coordinator {} told us of {} new coordinators", coordinator, discovered);
             }
-            Ok(())
         }
-        _ => Err(anyhow::anyhow!("unknown http server request: {:?}", message.body())),
     }
+    dispatch_next_job(state, workers, transport)?;
+    save_state(transport, state)
 }
 
 fn handle_message(
-    _our: &Address,
     state: &mut State, 
-    channel_ids: &mut HashSet<u32>,
+    workers: &mut WorkerPool,
+    transport: &mut dyn Transport,
 ) -> anyhow::Result<()> {
-    let message = await_message()?;
+    match transport.receive()? {
+        Incoming::SendError { kind, context } => {
+            return handle_send_error(state, transport, &kind, context.as_deref());
+        }
+        Incoming::Http(req) => handle_http_request(state, workers, transport, req)?,
+        Incoming::WebSocketOpen(channel_id) => handle_websocket_open(state, workers, transport, channel_id)?,
+        Incoming::WebSocketClose(channel_id) => handle_websocket_close(state, workers, transport, channel_id)?,
+        Incoming::WebSocketPush { channel_id, message_type, bytes } => {
+            handle_websocket_push(state, workers, transport, channel_id, message_type, bytes)?;
+        }
+        Incoming::Response { source, body, context } => {
+            handle_response(state, workers, transport, &source, &body, context.as_deref())?;
+        }
+        Incoming::Request { source, body, expects_response } => {
+            handle_coordinator_message(state, workers, transport, &source, &body, expects_response)?;
+        }
+    }
+    schedule_recovery(state, transport)
+}
+
+/// Settle what the journal says was in flight when the process stopped: the
+/// current and queued jobs resume, finished ones are reported again if that
+/// may not have happened, the rest are failed so the coordinator can retry.
+fn replay_journal(state: &mut State, transport: &mut dyn Transport) -> anyhow::Result<()> {
+    let records = state.journal.replay()?;
+    let mut open = Vec::new();
+    for mut record in records {
+        if record.is_settled() {
+            continue;
+        }
+        let current = state.current_job_id() == Some(record.id());
 
-    if message.source().process == "http_server:distro:sys" {
-        handle_http_server_message(state, channel_ids, &message)?;
-    } else {
-        handle_coordinator_message(state, channel_ids, &message)?;
+        if let Some(outcome) = record.outcome.clone() {
+            // Outbox entries were already resent by init
+            if !state.outbox.contains(record.id()) {
+                kiprintln!("reporting journaled outcome of {} again", record.id());
+                report_outcome(state, transport, outcome.clone())?;
+            }
+            if state.outbox.contains(record.id()) {
+                open.push(record.clone());
+            }
+            if current {
+                let event = match outcome {
+                    JobOutcome::Completed(result) => ProviderEvent::CompleteWork(result),
+                    JobOutcome::BatchCompleted(result) => ProviderEvent::CompleteBatch(result),
+                    JobOutcome::Failed(error) => ProviderEvent::FailWork { error },
+                };
+                state.transition(event)?;
+            }
+            continue;
+        }
+        if state.coordinator.is_none() {
+            kiprintln!("dropping journaled job {}: no coordinator to report to", record.id());
+            continue;
+        }
+
+        if current {
+            // Goes back out once a worker connects, see WebSocketOpen
+            kiprintln!("resuming {} at {}%", record.id(), record.progress.unwrap_or(0));
+            transport.set_timer(
+                state.job_timeout_ms(&record.assignment),
+                serde_json::to_vec(&PendingContext::JobDeadline {
+                    job_id: record.id().to_string(),
+                })?,
+            );
+            open.push(record);
+        } else if state.job_queue.iter().any(|queued| queued.id() == record.id()) {
+            open.push(record);
+        } else {
+            kiprintln!("failing journaled job {}: lost in a restart", record.id());
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            let reason = "provider restarted".to_string();
+            let outcome = match &record.assignment {
+                Assignment::Single(request) => JobOutcome::Failed(WorkError {
+                    id: request.id.clone(),
+                    error: reason,
+                    kind: WorkErrorKind::Other,
+                    timestamp,
+                }),
+                Assignment::Batch(batch) => JobOutcome::BatchCompleted(BatchResult {
+                    batch_id: batch.id.clone(),
+                    results: batch.items.iter()
+                        .map(|item| ItemResult {
+                            id: item.id.clone(),
+                            outcome: ItemOutcome::Failed { error: reason.clone() },
+                        })
+                        .collect(),
+                    timestamp,
+                }),
+            };
+            report_outcome(state, transport, outcome.clone())?;
+            if state.outbox.contains(record.id()) {
+                record.outcome = Some(outcome);
+                open.push(record);
+            }
+        }
     }
-    Ok(())
+
+    // The compacted journal only keeps jobs that are still open
+    state.journal.rewrite(&open)?;
+    save_state(transport, state)
 }
 
 fn serve_http_and_bind_paths(our: &Address) -> anyhow::Result<HttpServer> {
     let mut server = HttpServer::new(5);
     
-    let config = HttpBindingConfig::new(false, false, false, None);
+    // Only the node owner's logged-in browser may drive the provider
+    let config = HttpBindingConfig::new(true, false, false, None);
 
     match server.bind_ws_path("/", http::server::WsBindingConfig::new(
-        false, false, false, false
+        true, false, false, false
     )) {
         Ok(_) => println!("Successfully bound WebSocket path"),
         Err(e) => println!("Failed to bind WebSocket path: {:?}", e),
@@ -401,6 +1644,11 @@ fn serve_http_and_bind_paths(our: &Address) -> anyhow::Result<HttpServer> {
 
     server.bind_http_path("/register_provider", config.clone())?;
     server.bind_http_path("/coordinators", config.clone())?;
+    server.bind_http_path("/settings", config.clone())?;
+    server.bind_http_path("/models", config.clone())?;
+    server.bind_http_path("/workers", config.clone())?;
+    server.bind_http_path("/schema", config.clone())?;
+    server.bind_http_path("/transitions", config.clone())?;
 
     // Serve UI
     server.serve_ui(our, "ui", vec!["/"], config)?;
@@ -409,25 +1657,66 @@ fn serve_http_and_bind_paths(our: &Address) -> anyhow::Result<HttpServer> {
 }
 
 
+#[cfg(not(test))]
 call_init!(init);
+#[cfg_attr(test, allow(dead_code))]
 fn init(our: Address) -> anyhow::Result<()> {
     println!("provider: begin");
 
-    let mut state = match kinode_process_lib::get_state() {
-        Some(bytes) => bincode::deserialize(&bytes)
-            .unwrap_or_else(|_| State::new()),
-        None => State::new()
-    };
+    let mut transport = KinodeTransport::new(our.clone());
+    let mut state = persist::load(&our);
     //let mut state = State::new();
+    state.health.process_started();
+    // Timers do not survive a restart
+    state.recovery.scheduled = false;
+
+    match AuditLog::open(&our) {
+        Ok(audit) => state.audit = audit,
+        Err(e) => kiprintln!("failed to open audit log, transitions are only kept in memory: {e}"),
+    }
+    match Journal::open(&our) {
+        Ok(journal) => state.journal = journal,
+        Err(e) => kiprintln!("failed to open job journal, jobs will not survive a restart: {e}"),
+    }
+    // Reports the coordinator had not acknowledged before the restart
+    for job_id in state.outbox.job_ids() {
+        if let Err(e) = send_delivery(&mut state, &mut transport, &job_id) {
+            kiprintln!("failed to resend report on {}: {e}", job_id);
+        }
+    }
+    if let Err(e) = replay_journal(&mut state, &mut transport) {
+        kiprintln!("failed to replay job journal: {e}");
+    }
+
+    match state.coordinators.seed_from_config(&our) {
+        Ok(added) => kiprintln!("seeded {} coordinators from config", added),
+        Err(e) => kiprintln!("failed to seed coordinators from config: {e}"),
+    }
+    if let Err(e) = schedule_coordinator_refresh(&state, &mut transport) {
+        kiprintln!("failed to schedule coordinator discovery: {e}");
+    }
+    // Restarted while Failed
+    if let Err(e) = schedule_recovery(&mut state, &mut transport) {
+        kiprintln!("failed to schedule recovery: {e}");
+    }
 
-    let mut channel_ids: HashSet<u32> = HashSet::new();
+    let mut workers = WorkerPool::new();
     
     let mut _http_server = serve_http_and_bind_paths(&our)
         .expect("failed to bind paths");
 
     loop {
-        if let Err(e) = handle_message(&our, &mut state, &mut channel_ids) {
-            kiprintln!("Error handling message: {e}");
-        }
+        handle_next(&mut state, &mut workers, &mut transport);
+    }
+}
+
+/// Handle one message and publish what it changed.
+fn handle_next(state: &mut State, workers: &mut WorkerPool, transport: &mut dyn Transport) {
+    if let Err(e) = handle_message(state, workers, transport) {
+        kiprintln!("Error handling message: {e}");
+    }
+    // Also after errors, which may come after a transition
+    if let Err(e) = publish_state(state, workers, transport) {
+        kiprintln!("failed to publish state: {e}");
     }
 }
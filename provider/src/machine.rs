@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+
+use crate::structs::{ProviderEvent, ProviderState};
+
+// ProviderState without its payload
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum StateKind {
+    Unbound,
+    Idle,
+    Offline,
+    Working,
+    WorkingBatch,
+    Failed,
+}
+
+impl StateKind {
+    pub fn of(state: &ProviderState) -> Self {
+        match state {
+            ProviderState::Unbound => StateKind::Unbound,
+            ProviderState::Idle => StateKind::Idle,
+            ProviderState::Offline => StateKind::Offline,
+            ProviderState::Working { .. } => StateKind::Working,
+            ProviderState::WorkingBatch { .. } => StateKind::WorkingBatch,
+            ProviderState::Failed { .. } => StateKind::Failed,
+        }
+    }
+}
+
+// ProviderEvent without its payload
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum EventKind {
+    RegisterWithCoordinator,
+    StartWork,
+    StartBatch,
+    CompleteWork,
+    CompleteBatch,
+    FailWork,
+    TimeOut,
+    UpdateProgress,
+    Kicked,
+    GoOffline,
+    GoOnline,
+}
+
+impl EventKind {
+    pub fn of(event: &ProviderEvent) -> Self {
+        match event {
+            ProviderEvent::RegisterWithCoordinator(_) => EventKind::RegisterWithCoordinator,
+            ProviderEvent::StartWork(_) => EventKind::StartWork,
+            ProviderEvent::StartBatch(_) => EventKind::StartBatch,
+            ProviderEvent::CompleteWork(_) => EventKind::CompleteWork,
+            ProviderEvent::CompleteBatch(_) => EventKind::CompleteBatch,
+            ProviderEvent::FailWork { .. } => EventKind::FailWork,
+            ProviderEvent::TimeOut { .. } => EventKind::TimeOut,
+            ProviderEvent::UpdateProgress(_) => EventKind::UpdateProgress,
+            ProviderEvent::Kicked => EventKind::Kicked,
+            ProviderEvent::GoOffline => EventKind::GoOffline,
+            ProviderEvent::GoOnline(_) => EventKind::GoOnline,
+        }
+    }
+}
+
+/// Condition on the event's payload, checked once a rule matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Guard {
+    Always,
+    // The event is about the job being worked on
+    CurrentJob,
+}
+
+impl Guard {
+    /// None if the guard holds, otherwise why not.
+    pub fn check(&self, state: &ProviderState, event: &ProviderEvent) -> Option<String> {
+        match self {
+            Guard::Always => None,
+            Guard::CurrentJob => {
+                let current = match state {
+                    ProviderState::Working { request, .. } => Some(request.id.as_str()),
+                    ProviderState::WorkingBatch { batch, .. } => Some(batch.id.as_str()),
+                    _ => None,
+                };
+                let job_id = match event {
+                    ProviderEvent::CompleteWork(result) => Some(result.id.as_str()),
+                    ProviderEvent::CompleteBatch(result) => Some(result.batch_id.as_str()),
+                    ProviderEvent::FailWork { error } | ProviderEvent::TimeOut { error } => {
+                        Some(error.id.as_str())
+                    }
+                    _ => None,
+                };
+                (job_id != current).then(|| format!(
+                    "event is about {:?}, not the current job {:?}",
+                    job_id, current
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    To(StateKind),
+    // Keep the state, e.g. to record progress on it
+    Stay,
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    pub from: &'static [StateKind],
+    pub event: EventKind,
+    pub guard: Guard,
+    pub next: Next,
+}
+
+use EventKind as E;
+use StateKind as S;
+
+const ANY: &[StateKind] = &[S::Unbound, S::Idle, S::Offline, S::Working, S::WorkingBatch, S::Failed];
+const READY: &[StateKind] = &[S::Idle, S::Failed];
+const WORKING: &[StateKind] = &[S::Working, S::WorkingBatch];
+// Bound or bindable and not in the middle of a job
+const NOT_WORKING: &[StateKind] = &[S::Unbound, S::Idle, S::Offline, S::Failed];
+
+/// Every transition the provider may make. An event in a state without a
+/// rule is rejected and the state is left alone.
+pub const TRANSITIONS: &[Rule] = &[
+    // Registration and binding
+    Rule { from: NOT_WORKING, event: E::RegisterWithCoordinator, guard: Guard::Always, next: Next::To(S::Idle) },
+    Rule { from: &[S::Idle, S::Offline, S::Failed], event: E::GoOnline, guard: Guard::Always, next: Next::To(S::Idle) },
+    // A still-bound check while working must not drop the job
+    Rule { from: WORKING, event: E::GoOnline, guard: Guard::Always, next: Next::Stay },
+    Rule { from: READY, event: E::GoOffline, guard: Guard::Always, next: Next::To(S::Offline) },
+    Rule { from: ANY, event: E::Kicked, guard: Guard::Always, next: Next::To(S::Unbound) },
+
+    // Work lifecycle, a failed provider takes the job rather than dropping it
+    Rule { from: READY, event: E::StartWork, guard: Guard::Always, next: Next::To(S::Working) },
+    Rule { from: READY, event: E::StartBatch, guard: Guard::Always, next: Next::To(S::WorkingBatch) },
+    Rule { from: WORKING, event: E::UpdateProgress, guard: Guard::Always, next: Next::Stay },
+    Rule { from: &[S::Working], event: E::CompleteWork, guard: Guard::CurrentJob, next: Next::To(S::Idle) },
+    Rule { from: &[S::WorkingBatch], event: E::CompleteBatch, guard: Guard::CurrentJob, next: Next::To(S::Idle) },
+    Rule { from: WORKING, event: E::FailWork, guard: Guard::CurrentJob, next: Next::To(S::Failed) },
+    // Timed out jobs do not fail the provider, the next job may run fine
+    Rule { from: WORKING, event: E::TimeOut, guard: Guard::CurrentJob, next: Next::To(S::Idle) },
+];
+
+/// The rule for `event` in `state`, or why there is none.
+pub fn find(state: &ProviderState, event: &ProviderEvent) -> Result<&'static Rule, String> {
+    let from = StateKind::of(state);
+    let kind = EventKind::of(event);
+    let rule = TRANSITIONS.iter()
+        .find(|rule| rule.event == kind && rule.from.contains(&from))
+        .ok_or_else(|| format!("no transition from {:?} on {:?}", from, kind))?;
+    match rule.guard.check(state, event) {
+        Some(reason) => Err(reason),
+        None => Ok(rule),
+    }
+}
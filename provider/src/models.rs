@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+
+use crate::structs::{Modality, ModelCapabilities, ModelSpec, Normalization, Quantization};
+
+/// Upper bound on the registry, every model is offered to coordinators.
+const MAX_MODELS: usize = 32;
+
+/// Models this provider can run, with the metadata coordinators match on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelRegistry {
+    models: Vec<ModelSpec>,
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        // What the bundled UI runs: CLIP ViT-B/16 on WebGPU, both towers
+        Self {
+            models: vec![ModelSpec {
+                id: "clip-vit-base-patch16".to_string(),
+                revision: "main".to_string(),
+                dimension: 512,
+                modalities: vec![Modality::Image, Modality::Text],
+                quantization: Quantization::Fp32,
+                normalization: Normalization::L2,
+                expected_runtime_ms: 500,
+            }],
+        }
+    }
+}
+
+impl ModelRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ModelSpec> {
+        self.models.iter().find(|m| m.id == id)
+    }
+
+    pub fn list(&self) -> &[ModelSpec] {
+        &self.models
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.models.iter().map(|m| m.id.clone()).collect()
+    }
+
+    /// Whether `model` is registered and can embed `modality` inputs.
+    pub fn supports(&self, model: &str, modality: Modality) -> bool {
+        self.get(model).is_some_and(|m| m.modalities.contains(&modality))
+    }
+
+    pub fn capabilities(&self) -> Vec<ModelCapabilities> {
+        self.models.iter().map(ModelSpec::capabilities).collect()
+    }
+
+    /// Add a model or replace the entry with the same id.
+    pub fn upsert(&mut self, spec: ModelSpec) -> Result<(), String> {
+        if spec.id.trim().is_empty() {
+            return Err("model id must not be empty".to_string());
+        }
+        if spec.dimension == 0 {
+            return Err("dimension must be positive".to_string());
+        }
+        if spec.modalities.is_empty() {
+            return Err("model must support at least one modality".to_string());
+        }
+        if let Some(known) = self.models.iter_mut().find(|m| m.id == spec.id) {
+            *known = spec;
+            return Ok(());
+        }
+        if self.models.len() >= MAX_MODELS {
+            return Err(format!("registry is limited to {} models", MAX_MODELS));
+        }
+        self.models.push(spec);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<ModelSpec> {
+        let index = self.models.iter().position(|m| m.id == id)?;
+        Some(self.models.remove(index))
+    }
+}
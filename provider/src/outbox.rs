@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use kinode_process_lib::Address;
+
+use crate::journal::JobOutcome;
+
+/// How long to wait for the coordinator's Ack before resending.
+pub const DELIVERY_TIMEOUT_SECS: u64 = 10;
+/// Resend delay after the first failed attempt, doubled after every further one.
+const RETRY_BASE_MS: u64 = 1_000;
+const RETRY_MAX_MS: u64 = 60_000;
+/// Attempts before a report is given up on, about half an hour of retries.
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 40;
+/// Given up reports kept for the UI, newest last.
+const MAX_ABANDONED: usize = 20;
+
+// A job outcome the coordinator has not acknowledged yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub job_id: String,
+    // Where the job came from, which may no longer be our coordinator
+    pub coordinator: Address,
+    pub outcome: JobOutcome,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+impl Delivery {
+    pub fn retry_delay_ms(&self) -> u64 {
+        RETRY_BASE_MS
+            .saturating_mul(1u64 << self.attempts.saturating_sub(1).min(16))
+            .min(RETRY_MAX_MS)
+    }
+}
+
+/// Reports waiting for an Ack. Persisted with the state so they are
+/// resent after a restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Outbox {
+    deliveries: Vec<Delivery>,
+    // Reports that ran out of attempts, so the UI can show what never arrived
+    #[serde(default)]
+    abandoned: Vec<Delivery>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue an outcome, replacing an older one for the same job.
+    pub fn push(&mut self, coordinator: Address, outcome: JobOutcome) {
+        let job_id = outcome.job_id().to_string();
+        self.deliveries.retain(|d| d.job_id != job_id);
+        self.deliveries.push(Delivery {
+            job_id,
+            coordinator,
+            outcome,
+            attempts: 0,
+            last_error: None,
+        });
+    }
+
+    pub fn get(&self, job_id: &str) -> Option<&Delivery> {
+        self.deliveries.iter().find(|d| d.job_id == job_id)
+    }
+
+    pub fn get_mut(&mut self, job_id: &str) -> Option<&mut Delivery> {
+        self.deliveries.iter_mut().find(|d| d.job_id == job_id)
+    }
+
+    pub fn contains(&self, job_id: &str) -> bool {
+        self.get(job_id).is_some()
+    }
+
+    pub fn remove(&mut self, job_id: &str) -> Option<Delivery> {
+        let index = self.deliveries.iter().position(|d| d.job_id == job_id)?;
+        Some(self.deliveries.remove(index))
+    }
+
+    /// Give up on a delivery, keeping it among the abandoned ones.
+    pub fn abandon(&mut self, job_id: &str) -> bool {
+        let Some(delivery) = self.remove(job_id) else {
+            return false;
+        };
+        self.abandoned.push(delivery);
+        if self.abandoned.len() > MAX_ABANDONED {
+            self.abandoned.remove(0);
+        }
+        true
+    }
+
+    pub fn deliveries(&self) -> &[Delivery] {
+        &self.deliveries
+    }
+
+    pub fn abandoned(&self) -> &[Delivery] {
+        &self.abandoned
+    }
+
+    pub fn job_ids(&self) -> Vec<String> {
+        self.deliveries.iter().map(|d| d.job_id.clone()).collect()
+    }
+}
@@ -0,0 +1,139 @@
+use kinode_process_lib::{Address, vfs};
+
+use protocol::StateEnvelope;
+
+use crate::structs::{ProviderState, State, WorkError, WorkErrorKind};
+
+/// Version written by this build. Bump it together with a new migration
+/// whenever a change to `State` is not covered by `#[serde(default)]`.
+pub const STATE_VERSION: u32 = 1;
+/// Bytes without its magic are the bare bincode state written before
+/// states were versioned.
+const ENVELOPE: StateEnvelope = StateEnvelope {
+    magic: b"HPST",
+    version: STATE_VERSION,
+    migrations: &[],
+};
+/// Drive holding states that could not be decoded.
+const BACKUP_DRIVE: &str = "state_backups";
+/// Timeout for a single vfs call.
+const VFS_TIMEOUT_SECS: u64 = 5;
+
+pub fn encode(state: &State) -> anyhow::Result<Vec<u8>> {
+    ENVELOPE.encode(state).map_err(anyhow::Error::msg)
+}
+
+pub fn decode(bytes: &[u8]) -> anyhow::Result<State> {
+    if !ENVELOPE.wraps(bytes) {
+        kiprintln!("migrating unversioned state to v{}", STATE_VERSION);
+        return Ok(migrate_legacy(bincode::deserialize(bytes)?));
+    }
+    ENVELOPE.decode(bytes).map_err(anyhow::Error::msg)
+}
+
+/// The unversioned state as the last build without the envelope wrote it.
+/// Frozen: it must keep decoding those bytes whatever `State` turns into.
+pub mod legacy {
+    use serde::{Deserialize, Serialize};
+    use kinode_process_lib::Address;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct State {
+        pub state: ProviderState,
+        pub coordinator: Option<Address>,
+        pub supported_models: Vec<String>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub enum ProviderState {
+        Unbound,
+        Idle,
+        Offline,
+        Working {
+            request: WorkRequest,
+            progress: Option<u32>,
+        },
+        Failed {
+            error: WorkError,
+        },
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct WorkRequest {
+        pub id: String,
+        pub model: String,
+        pub uri: String,
+        pub timestamp: u64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct WorkError {
+        pub id: String,
+        pub error: String,
+        pub timestamp: u64,
+    }
+}
+
+/// Carry a legacy state over into a fresh current one. Goes straight to the
+/// current version, so no envelope migration applies after it.
+pub fn migrate_legacy(legacy: legacy::State) -> State {
+    let mut state = State::new();
+    let bound = legacy.coordinator.is_some();
+    state.state = match legacy.state {
+        legacy::ProviderState::Offline => ProviderState::Offline,
+        _ if !bound => ProviderState::Unbound,
+        legacy::ProviderState::Unbound => ProviderState::Unbound,
+        legacy::ProviderState::Idle => ProviderState::Idle,
+        legacy::ProviderState::Working { request, .. } => {
+            // Nothing was journaled, the coordinator times the job out
+            kiprintln!("dropping job {} that was running before the upgrade", request.id);
+            ProviderState::Idle
+        }
+        legacy::ProviderState::Failed { error } => ProviderState::Failed {
+            error: WorkError {
+                id: error.id,
+                error: error.error,
+                kind: WorkErrorKind::Other,
+                timestamp: error.timestamp,
+            },
+        },
+    };
+    if bound {
+        // Bindings made before the version handshake speak version 0
+        state.protocol_version = 0;
+    }
+    state.coordinator = legacy.coordinator;
+    for model in legacy.supported_models.iter().filter(|model| state.models.get(model).is_none()) {
+        kiprintln!("no spec for previously supported model {}, it is no longer offered", model);
+    }
+    state
+}
+
+/// The saved state, or a fresh one if there is none. State that cannot be
+/// decoded is backed up to the vfs first, so nothing is lost silently.
+pub fn load(our: &Address) -> State {
+    let Some(bytes) = kinode_process_lib::get_state() else {
+        return State::new();
+    };
+    match decode(&bytes) {
+        Ok(state) => state,
+        Err(e) => {
+            kiprintln!("saved state is unreadable, starting fresh: {e}");
+            match backup(our, &bytes) {
+                Ok(path) => kiprintln!("unreadable state backed up to {}", path),
+                Err(e) => kiprintln!("failed to back up unreadable state: {e}"),
+            }
+            State::new()
+        }
+    }
+}
+
+fn backup(our: &Address, bytes: &[u8]) -> anyhow::Result<String> {
+    let drive = vfs::create_drive(our.package_id(), BACKUP_DRIVE, Some(VFS_TIMEOUT_SECS))?;
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let path = format!("{}/state-{}.bin", drive, timestamp);
+    vfs::create_file(&path, Some(VFS_TIMEOUT_SECS))?.write(bytes)?;
+    Ok(path)
+}
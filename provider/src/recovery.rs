@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+// How the provider gets itself out of the Failed state
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RecoverySettings {
+    // Failed jobs in a row before the provider takes itself offline
+    pub max_consecutive_failures: u32,
+    // Wait before the first recovery attempt, doubled for every retry
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+}
+
+impl Default for RecoverySettings {
+    fn default() -> Self {
+        Self {
+            max_consecutive_failures: 5,
+            backoff_base_ms: 2_000,
+            backoff_max_ms: 60_000,
+        }
+    }
+}
+
+impl RecoverySettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_consecutive_failures == 0 {
+            return Err("max_consecutive_failures must be positive".to_string());
+        }
+        if self.backoff_base_ms == 0 || self.backoff_base_ms > self.backoff_max_ms {
+            return Err("backoff_base_ms must be positive and at most backoff_max_ms".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Failure streak and the progress of getting out of Failed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Recovery {
+    pub consecutive_failures: u32,
+    // Recovery attempts since the provider last failed
+    pub attempt: u32,
+    // A recovery timer is running
+    pub scheduled: bool,
+    // Model of the job that failed last, warmed again before going Idle
+    pub model: Option<String>,
+}
+
+impl Recovery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn job_failed(&mut self, model: Option<&str>) {
+        self.consecutive_failures += 1;
+        if let Some(model) = model {
+            self.model = Some(model.to_string());
+        }
+    }
+
+    pub fn job_succeeded(&mut self) {
+        self.consecutive_failures = 0;
+    }
+
+    pub fn exhausted(&self, settings: &RecoverySettings) -> bool {
+        self.consecutive_failures >= settings.max_consecutive_failures
+    }
+
+    /// Delay before the next attempt, doubling up to the configured cap.
+    pub fn backoff_ms(&self, settings: &RecoverySettings) -> u64 {
+        settings.backoff_base_ms
+            .saturating_mul(1u64 << self.attempt.min(16))
+            .min(settings.backoff_max_ms)
+    }
+
+    /// Back to normal operation, the failure streak itself is kept.
+    pub fn recovered(&mut self) {
+        self.attempt = 0;
+        self.scheduled = false;
+    }
+}
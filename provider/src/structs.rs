@@ -1,54 +1,220 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use kinode_process_lib::{
-    Address, kiprintln,
-    LazyLoadBlob,
-    http,
-    http::server
+use std::collections::{HashSet, VecDeque};
+use crate::coordinators::CoordinatorRegistry;
+use crate::health::HealthStats;
+use crate::audit::{AuditEntry, AuditLog};
+use crate::journal::Journal;
+use crate::machine::{self, EventKind, Next, StateKind};
+use crate::models::ModelRegistry;
+use crate::outbox::Outbox;
+use crate::recovery::{Recovery, RecoverySettings};
+use crate::transport::Transport;
+use crate::ui_messages::{push_to_channel, ProviderPush, StateView};
+pub use protocol::{
+    BatchItem, BatchResult, ItemOutcome, ItemResult,
+    ChallengeInput, ChallengeOutput,
+    CoordinatorInfo, CoordinatorRequest, CoordinatorResponse,
+    EmbeddingEncoding, EmbeddingSpace, EncodedEmbeddings, HealthReport, Modality, ModelCapabilities, ModelSpec, ModelStatus,
+    Normalization, Quantization,
+    ProviderRequest, ProviderResponse, ProviderState,
+    EmbeddingViolation,
+    WorkBatch, WorkError, WorkErrorKind, WorkInput, WorkRequest, WorkResult,
 };
+use kinode_process_lib::Address;
 
 
+// Persisted through crate::persist. New fields need #[serde(default)] or a
+// migration there
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct State {
     pub state: ProviderState,
     pub coordinator: Option<Address>,
-    pub supported_models: Vec<String>,
+    pub models: ModelRegistry,
+    pub coordinators: CoordinatorRegistry,
+    // Protocol version negotiated with the bound coordinator
+    pub protocol_version: u32,
+    // Result encoding agreed with the bound coordinator, None for JSON
+    pub embedding_encoding: Option<EmbeddingEncoding>,
+    pub pending_registration: Option<PendingRegistration>,
+    pub health: HealthStats,
+    pub settings: ProviderSettings,
+    // Accepted jobs waiting for the current one to finish
+    pub job_queue: VecDeque<Assignment>,
+    pub batch_run: Option<BatchRun>,
+    pub recovery: Recovery,
+    #[serde(default)]
+    pub outbox: Outbox,
+    // Opened by init, the journal itself is the persistent part
+    #[serde(skip)]
+    pub journal: Journal,
+    // Changed since it was last pushed to the UI, see publish_state
+    #[serde(skip)]
+    pub unpublished: bool,
+    // Opened by init, persisted in the vfs like the journal
+    #[serde(skip)]
+    pub audit: AuditLog,
 }
 
+// Unit of work accepted from the coordinator
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub enum ProviderState {
-    Unbound,      // Online, no coordinator assigned
-    Idle,         // Online, connected to coordinator, ready for work
-    Offline,      // Offline
-    Working {     // Online, processing a request
-        request: WorkRequest,
-        progress: Option<u32>,
-    },
-    Failed {      // Online, error state, includes reason. Still bound to coordinator
-        error: WorkError,
-    },
+pub enum Assignment {
+    Single(WorkRequest),
+    Batch(WorkBatch),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct WorkRequest {
-    pub id: String,
-    pub model: String,
-    pub uri: String,
-    pub timestamp: u64,
+impl Assignment {
+    pub fn id(&self) -> &str {
+        match self {
+            Assignment::Single(request) => &request.id,
+            Assignment::Batch(batch) => &batch.id,
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        match self {
+            Assignment::Single(request) => &request.model,
+            Assignment::Batch(batch) => &batch.model,
+        }
+    }
+
+    pub fn inputs(&self) -> Vec<WorkInput> {
+        match self {
+            Assignment::Single(request) => vec![request.input()],
+            Assignment::Batch(batch) => batch.items.iter().map(|item| item.input()).collect(),
+        }
+    }
 }
 
+/// How many times a batch item is sent to the worker before it is reported
+/// as failed.
+pub const MAX_BATCH_ITEM_ATTEMPTS: u32 = 2;
+
+// Results collected so far for the batch being worked on
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct WorkResult {
-    pub id: String,
-    pub embeddings: Vec<f32>,
-    pub timestamp: u64,
+pub struct BatchRun {
+    pub batch_id: String,
+    pub results: Vec<ItemResult>,
+    pub attempt: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct WorkError {
-    pub id: String,
-    pub error: String,
-    pub timestamp: u64,
+/// Without a timeout from the coordinator a job may take this many times
+/// its model's expected runtime.
+pub const JOB_TIMEOUT_FACTOR: u64 = 20;
+/// Floor for default timeouts, leaves room for a cold model download.
+pub const MIN_JOB_TIMEOUT_MS: u64 = 60_000;
+
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 4;
+/// Hard cap on the configurable queue depth.
+pub const MAX_QUEUE_DEPTH_LIMIT: usize = 64;
+
+// Operator-tunable provider behaviour
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderSettings {
+    pub max_queue_depth: usize,
+    // Offered to coordinators when registering, preferred first. Lossy
+    // encodings are only offered once the user adds them
+    pub embedding_encodings: Vec<EmbeddingEncoding>,
+    pub recovery: RecoverySettings,
+    // Coordinators we may register with, empty allows any
+    #[serde(default)]
+    pub coordinator_allowlist: Vec<Address>,
+}
+
+impl Default for ProviderSettings {
+    fn default() -> Self {
+        Self {
+            max_queue_depth: DEFAULT_MAX_QUEUE_DEPTH,
+            embedding_encodings: vec![EmbeddingEncoding::F32Le],
+            recovery: RecoverySettings::default(),
+            coordinator_allowlist: Vec::new(),
+        }
+    }
+}
+
+impl ProviderSettings {
+    pub fn allows_coordinator(&self, coordinator: &Address) -> bool {
+        self.coordinator_allowlist.is_empty() || self.coordinator_allowlist.contains(coordinator)
+    }
+}
+
+// Body of a /settings request from the UI, absent fields are left unchanged
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SettingsUpdate {
+    #[serde(default)]
+    pub max_queue_depth: Option<usize>,
+    #[serde(default)]
+    pub embedding_encodings: Option<Vec<EmbeddingEncoding>>,
+    #[serde(default)]
+    pub recovery: Option<RecoverySettings>,
+    #[serde(default)]
+    pub coordinator_allowlist: Option<Vec<Address>>,
+}
+
+impl SettingsUpdate {
+    /// Check every field, so a bad one leaves all settings unchanged.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(recovery) = &self.recovery {
+            recovery.validate()?;
+        }
+        if self.max_queue_depth.is_some_and(|depth| depth > MAX_QUEUE_DEPTH_LIMIT) {
+            return Err(format!("max_queue_depth must be at most {}", MAX_QUEUE_DEPTH_LIMIT));
+        }
+        Ok(())
+    }
+
+    /// Apply a validated update.
+    pub fn apply(self, settings: &mut ProviderSettings) {
+        if let Some(allowlist) = self.coordinator_allowlist {
+            // Applies to the next registration, not to a binding we already have
+            settings.coordinator_allowlist = allowlist;
+        }
+        if let Some(recovery) = self.recovery {
+            settings.recovery = recovery;
+        }
+        if let Some(encodings) = self.embedding_encodings {
+            // Takes effect with the next registration
+            settings.embedding_encodings = encodings;
+        }
+        if let Some(depth) = self.max_queue_depth {
+            settings.max_queue_depth = depth;
+        }
+    }
+}
+
+// Registration waiting on the UI to answer the coordinator's challenge
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingRegistration {
+    pub coordinator: Address,
+    pub challenge_id: String,
+    pub inputs: Vec<ChallengeInput>,
+    pub outputs: Vec<ChallengeOutput>,
+}
+
+impl PendingRegistration {
+    pub fn is_complete(&self) -> bool {
+        self.inputs.iter()
+            .all(|input| self.outputs.iter().any(|output| output.model == input.model))
+    }
+}
+
+// Context of our timers, to tell them apart when they fire
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PendingContext {
+    // The job's worker disconnected and no other worker could take it
+    OrphanedJob { job_id: String },
+    // The job ran out of time
+    JobDeadline { job_id: String },
+    // Next attempt at leaving the Failed state
+    Recover,
+    // Report sent, waiting for the coordinator's Ack
+    Delivery { job_id: String },
+    // Resend a report that was not acknowledged
+    RetryDelivery { job_id: String },
+    // Ask the known coordinators for their peers. Only the periodic round
+    // schedules the next one, a forced refresh does not.
+    RefreshCoordinators { periodic: bool },
+    // GetPeers sent to a known coordinator during discovery
+    Peers { coordinator: Address, sent_at_ms: u64 },
 }
 
 // Messages that can trigger state transitions
@@ -56,32 +222,15 @@ pub struct WorkError {
 pub enum ProviderEvent {
     RegisterWithCoordinator(Address),
     StartWork(WorkRequest),
+    StartBatch(WorkBatch),
     CompleteWork(WorkResult),
+    CompleteBatch(BatchResult),
     FailWork { error: WorkError },
+    TimeOut { error: WorkError },
     UpdateProgress(u32),
     Kicked,
     GoOffline,
     GoOnline(Address),
-    Error(String),
-}
-
-// Unprompted messages from provider to coordinator
-#[derive(Debug, Serialize, Deserialize)]
-pub enum CoordinatorRequest {
-    RegisterProvider { supported_models: Vec<String> },
-    ProviderReady,
-    GoOffline,
-}
-
-// Prompted responses from coordinator to provider
-#[derive(Debug, Serialize, Deserialize)]
-pub enum CoordinatorResponse {
-    ProviderRegistered { required_models: Vec<String> },
-    RegistrationRejected { reason: String },
-    NoWorkAvailable,
-    Ack,
-    Nack,
-    Error(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -91,31 +240,13 @@ pub enum ProviderStatus {
     Offline,
 }
 
-// Unprompted Coordinator to Provider messages
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub enum ProviderRequest {
-    HealthPing,
-    AssignWork(WorkRequest),
-    Kick,
-}
-
+// Body of a /register_provider request from the UI
 #[derive(Debug, Serialize, Deserialize)]
-pub enum ProviderResponse {
-    HealthPong,
-    WorkAssigned,
-    WorkCompleted { 
-        result: WorkResult 
-    },
-    WorkFailed { 
-        error: WorkError 
-    },
-    Error(String),
-}
-// Messages for UI communication
-#[derive(Debug, Serialize, Deserialize)]
-pub struct WebSocketMessage {
-    pub message_type: String,
-    pub data: serde_json::Value,
+pub struct RegisterRequest {
+    pub coordinator_address: String,
+    // Models to offer the coordinator, defaults to everything we support
+    #[serde(default)]
+    pub supported_models: Option<Vec<String>>,
 }
 
 impl State {
@@ -123,144 +254,181 @@ impl State {
         Self {
             state: ProviderState::Unbound,
             coordinator: None,
-            supported_models: vec!["clip-vit-base-patch16".to_string()],
+            models: ModelRegistry::new(),
+            coordinators: CoordinatorRegistry::new(),
+            protocol_version: protocol::PROTOCOL_VERSION,
+            embedding_encoding: None,
+            pending_registration: None,
+            health: HealthStats::new(),
+            settings: ProviderSettings::default(),
+            job_queue: VecDeque::new(),
+            batch_run: None,
+            recovery: Recovery::new(),
+            outbox: Outbox::new(),
+            journal: Journal::default(),
+            unpublished: false,
+            audit: AuditLog::default(),
         }
     }
 
-    pub fn safe_transition(&mut self, event: ProviderEvent, channel_id: u32) -> anyhow::Result<()> {
+    /// How long `assignment` may run before it is failed.
+    pub fn job_timeout_ms(&self, assignment: &Assignment) -> u64 {
+        let default = self.models.get(assignment.model())
+            .map(|spec| spec.expected_runtime_ms.saturating_mul(JOB_TIMEOUT_FACTOR))
+            .unwrap_or(0)
+            .max(MIN_JOB_TIMEOUT_MS);
+        match assignment {
+            Assignment::Single(request) => request.timeout_ms.unwrap_or(default),
+            Assignment::Batch(batch) => default.saturating_mul(batch.items.len().max(1) as u64),
+        }
+    }
+
+    /// Whether `source` may send us ProviderRequests: the bound coordinator,
+    /// or before a binding exists the allowed coordinator we are registering
+    /// with.
+    pub fn accepts_requests_from(&self, source: &Address) -> bool {
+        match &self.coordinator {
+            Some(coordinator) => coordinator == source,
+            None => self.pending_registration.as_ref()
+                .is_some_and(|pending| &pending.coordinator == source)
+                && self.settings.allows_coordinator(source),
+        }
+    }
+
+    /// Id of the job or batch being computed right now.
+    pub fn current_job_id(&self) -> Option<&str> {
+        match &self.state {
+            ProviderState::Working { request, .. } => Some(&request.id),
+            ProviderState::WorkingBatch { batch, .. } => Some(&batch.id),
+            _ => None,
+        }
+    }
+
+    /// Transition and persist. Tabs see the change once the message that
+    /// caused it has been handled.
+    pub fn safe_transition(
+        &mut self,
+        transport: &mut dyn Transport,
+        event: ProviderEvent,
+    ) -> anyhow::Result<()> {
         self.transition(event)?;
-        save_state(self)?;
-        notify_ui_state_change(self, &channel_id)?;
-        Ok(())
+        save_state(transport, self)
+    }
+
+    /// Something the UI shows changed outside of a transition.
+    pub fn mark_unpublished(&mut self) {
+        self.unpublished = true;
+    }
+
+    /// Whether `event` would be accepted in the current state.
+    pub fn allows(&self, event: &ProviderEvent) -> bool {
+        machine::find(&self.state, event).is_ok()
     }
 
+    /// Apply `event` as allowed by machine::TRANSITIONS, recording it in the
+    /// audit log either way.
     pub fn transition(&mut self, event: ProviderEvent) -> anyhow::Result<()> {
-        use ProviderEvent::*;
-        use ProviderState::*;
-
-        self.state = match (&self.state, event) {
-            // Registration
-            (Unbound, RegisterWithCoordinator(addr)) => {
-                kiprintln!("Transitioning from Unbound to Idle with coordinator");
-                self.coordinator = Some(addr);
-                Idle
+        let rule = match machine::find(&self.state, &event) {
+            Ok(rule) => rule,
+            Err(reason) => {
+                kiprintln!("rejecting transition: {}", reason);
+                self.audit.record(AuditEntry::new(&self.state, &event, Err(&reason)));
+                return Err(anyhow::anyhow!("invalid state transition: {}", reason));
             }
+        };
 
-            (Idle, GoOffline) => {
-                kiprintln!("Transitioning from Idle to Offline");
-                Offline
-            },
-            
-            // Work lifecycle
-            (Idle, StartWork(req)) => {
-                kiprintln!("Transitioning from Idle to Working");
-                Working {
-                    request: req,
-                    progress: None,
-                }
-            },
-            (Working { request, .. }, CompleteWork(result)) if request.id == result.id => {
-                kiprintln!("Transitioning from Working to Idle - work completed");
-                Idle
-            },
-            (Working { request, .. }, FailWork { error }) if request.id == error.id=> {
-                kiprintln!("Transitioning from Working to Failed");
-                Failed { error }
-            },
-            (Working { request, .. }, UpdateProgress(p)) => {
-                kiprintln!("Updating work progress to {}", p);
-                Working {
+        let next = match (rule.next, &event) {
+            (Next::Stay, ProviderEvent::UpdateProgress(p)) => match &self.state {
+                ProviderState::Working { request, .. } => ProviderState::Working {
                     request: request.clone(),
-                    progress: Some(p),
-                }
+                    progress: Some(*p),
+                },
+                ProviderState::WorkingBatch { batch, .. } => ProviderState::WorkingBatch {
+                    batch: batch.clone(),
+                    progress: Some(*p),
+                },
+                state => state.clone(),
             },
-
-            (Failed { .. }, GoOnline(addr)) => {
-                kiprintln!("Transitioning from Failed to Idle via GoOnline");
-                self.coordinator = Some(addr);
-                Idle
-            },
-            (Failed { .. }, StartWork(_)) => {
-                kiprintln!("Transitioning from Failed to Idle via StartWork");
-                Idle
+            (Next::Stay, _) => self.state.clone(),
+            (Next::To(StateKind::Unbound), _) => ProviderState::Unbound,
+            (Next::To(StateKind::Idle), _) => ProviderState::Idle,
+            (Next::To(StateKind::Offline), _) => ProviderState::Offline,
+            (Next::To(StateKind::Working), ProviderEvent::StartWork(request)) => ProviderState::Working {
+                request: request.clone(),
+                progress: None,
             },
-            (Failed { .. }, RegisterWithCoordinator(addr)) => {
-                kiprintln!("Transitioning from Failed to Idle via RegisterWithCoordinator");
-                self.coordinator = Some(addr);
-                Idle
+            (Next::To(StateKind::WorkingBatch), ProviderEvent::StartBatch(batch)) => ProviderState::WorkingBatch {
+                batch: batch.clone(),
+                progress: None,
             },
-
-            (_, RegisterWithCoordinator(addr)) => {
-                kiprintln!("Transitioning to Idle via RegisterWithCoordinator (catch-all)");
-                self.coordinator = Some(addr);
-                Idle
+            (Next::To(StateKind::Failed), ProviderEvent::FailWork { error }) => ProviderState::Failed {
+                error: error.clone(),
             },
+            (Next::To(kind), event) => return Err(anyhow::anyhow!(
+                "transition table leads to {:?} on {:?}, which has no payload for it",
+                kind, EventKind::of(event)
+            )),
+        };
 
-            (_, GoOnline(addr)) => {
-                kiprintln!("Transitioning to Idle via GoOnline (catch-all)");
-                self.coordinator = Some(addr);
-                Idle
-            },
-            
-            (_, Kicked) => {
-                kiprintln!("Transitioning to Unbound via Kick (catch-all)");
+        let model = self.current_model().map(str::to_string);
+        match &event {
+            ProviderEvent::RegisterWithCoordinator(addr) | ProviderEvent::GoOnline(addr) => {
+                self.coordinator = Some(addr.clone());
+            }
+            ProviderEvent::StartWork(_) => self.health.job_started(),
+            ProviderEvent::StartBatch(batch) => {
+                self.health.job_started();
+                self.batch_run = Some(BatchRun {
+                    batch_id: batch.id.clone(),
+                    results: Vec::new(),
+                    attempt: 1,
+                });
+            }
+            ProviderEvent::CompleteWork(_) | ProviderEvent::CompleteBatch(_) => {
+                self.health.job_completed();
+                self.recovery.job_succeeded();
+                self.batch_run = None;
+            }
+            ProviderEvent::FailWork { error } | ProviderEvent::TimeOut { error } => {
+                self.health.job_failed(&error.error);
+                self.recovery.job_failed(model.as_deref());
+                self.batch_run = None;
+            }
+            ProviderEvent::Kicked => {
                 self.coordinator = None;
-                Unbound
+                self.job_queue.clear();
+                self.batch_run = None;
             }
+            ProviderEvent::GoOffline | ProviderEvent::UpdateProgress(_) => {}
+        }
 
-            // Error handling
-            (_, Error(err)) => {
-                kiprintln!("Transitioning to Failed due to error: {}", err);
-                Failed { 
-                    error: WorkError { 
-                        id: "".to_string(), 
-                        error: err, 
-                        timestamp: 0 
-                    } 
-                }
-            },
-
-            // Invalid transitions
-            (current, event) => return Err(anyhow::anyhow!(
-                "Invalid state transition from {:?} with event {:?}", 
-                current, event
-            )),
-        };
-
+        if StateKind::of(&next) != StateKind::of(&self.state) {
+            kiprintln!("Transitioning from {:?} to {:?}", StateKind::of(&self.state), StateKind::of(&next));
+        }
+        self.audit.record(AuditEntry::new(&self.state, &event, Ok(&next)));
+        self.state = next;
+        self.unpublished = true;
         Ok(())
     }
+
+    fn current_model(&self) -> Option<&str> {
+        match &self.state {
+            ProviderState::Working { request, .. } => Some(&request.model),
+            ProviderState::WorkingBatch { batch, .. } => Some(&batch.model),
+            _ => None,
+        }
+    }
 }
 
 pub fn notify_ui_state_change(
+    transport: &mut dyn Transport,
     state: &State,
     channel_id: &u32,
 ) -> anyhow::Result<()> {
-    let state_update = serde_json::json!({
-        "type": "state_update",
-        "state": state,
-        "coordinator": state.coordinator.as_ref().map(|addr| addr.to_string())
-    });
-
-
-    http::server::send_ws_push(
-        *channel_id,
-        server::WsMessageType::Text,
-        LazyLoadBlob {
-            mime: Some("application/json".to_string()),
-            bytes: serde_json::to_vec(&state_update)?,
-        },
-    );
-
-    Ok(())
-}
-
-pub fn save_state(state: &State) -> anyhow::Result<()> {
-    kinode_process_lib::set_state(&bincode::serialize(state)?);
-    Ok(())
+    push_to_channel(transport, *channel_id, &ProviderPush::StateUpdate(StateView::of(state)))
 }
 
-pub fn update_state(state: &mut State, channel_id: u32) -> anyhow::Result<()> {
-    save_state(state)?;
-    notify_ui_state_change(state, &channel_id)?;
+pub fn save_state(transport: &mut dyn Transport, state: &State) -> anyhow::Result<()> {
+    transport.save_state(&crate::persist::encode(state)?);
     Ok(())
 }
@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use kinode_process_lib::{Address, LazyLoadBlob, SendErrorKind, http};
+
+use crate::structs::{CoordinatorRequest, CoordinatorResponse};
+use crate::transport::{Incoming, Transport};
+use crate::ui_messages::ProviderPush;
+
+/// Answers the provider's blocking calls to a coordinator, None to leave
+/// the call unanswered.
+pub type Coordinator = Box<dyn FnMut(&Address, CoordinatorRequest) -> Option<CoordinatorResponse>>;
+
+// A request sent without waiting, e.g. a report
+#[derive(Debug)]
+pub struct Sent {
+    pub target: Address,
+    pub body: Vec<u8>,
+    pub blob: Option<LazyLoadBlob>,
+    pub ack: Option<(u64, Vec<u8>)>,
+}
+
+/// In-memory transport: messages for the provider are queued up front and
+/// everything it sends is recorded for the test to look at.
+pub struct FakeTransport {
+    pub incoming: VecDeque<Incoming>,
+    // Answers to requests, in order
+    pub responses: Vec<Vec<u8>>,
+    pub calls: Vec<(Address, CoordinatorRequest)>,
+    pub sent: Vec<Sent>,
+    pub timers: Vec<(u64, Vec<u8>)>,
+    pub pushes: Vec<(u32, ProviderPush)>,
+    pub http: Vec<(http::StatusCode, serde_json::Value)>,
+    pub saved: Option<Vec<u8>>,
+    coordinator: Coordinator,
+}
+
+impl FakeTransport {
+    pub fn new(coordinator: Coordinator) -> Self {
+        Self {
+            incoming: VecDeque::new(),
+            responses: Vec::new(),
+            calls: Vec::new(),
+            sent: Vec::new(),
+            timers: Vec::new(),
+            pushes: Vec::new(),
+            http: Vec::new(),
+            saved: None,
+            coordinator,
+        }
+    }
+
+    /// A coordinator that takes every registration and acknowledges the rest.
+    pub fn accepting() -> Self {
+        Self::new(Box::new(|_, request| Some(match request {
+            CoordinatorRequest::RegisterProvider { supported_models, .. } => {
+                CoordinatorResponse::ProviderRegistered {
+                    required_models: supported_models,
+                    protocol_version: protocol::PROTOCOL_VERSION,
+                    encoding: None,
+                }
+            }
+            _ => CoordinatorResponse::Ack,
+        })))
+    }
+
+    /// Pushes sent to `channel_id` so far.
+    pub fn pushes_to(&self, channel_id: u32) -> Vec<&ProviderPush> {
+        self.pushes.iter()
+            .filter(|(channel, _)| *channel == channel_id)
+            .map(|(_, push)| push)
+            .collect()
+    }
+
+    pub fn last_response<T: serde::de::DeserializeOwned>(&self) -> T {
+        serde_json::from_slice(self.responses.last().expect("no response sent"))
+            .expect("response does not parse")
+    }
+
+    pub fn last_http(&self) -> &(http::StatusCode, serde_json::Value) {
+        self.http.last().expect("no http response sent")
+    }
+}
+
+impl Transport for FakeTransport {
+    fn receive(&mut self) -> anyhow::Result<Incoming> {
+        self.incoming.pop_front()
+            .ok_or_else(|| anyhow::anyhow!("no message queued"))
+    }
+
+    fn respond(&mut self, body: Vec<u8>) -> anyhow::Result<()> {
+        self.responses.push(body);
+        Ok(())
+    }
+
+    fn call(
+        &mut self,
+        target: &Address,
+        body: Vec<u8>,
+        _timeout_secs: u64,
+    ) -> anyhow::Result<Result<Vec<u8>, SendErrorKind>> {
+        let request: CoordinatorRequest = serde_json::from_slice(&body)?;
+        self.calls.push((target.clone(), serde_json::from_slice(&body)?));
+        match (self.coordinator)(target, request) {
+            Some(response) => Ok(Ok(serde_json::to_vec(&response)?)),
+            None => Ok(Err(SendErrorKind::Timeout)),
+        }
+    }
+
+    fn send(
+        &mut self,
+        target: &Address,
+        body: Vec<u8>,
+        blob: Option<LazyLoadBlob>,
+        ack: Option<(u64, Vec<u8>)>,
+    ) -> anyhow::Result<()> {
+        self.sent.push(Sent { target: target.clone(), body, blob, ack });
+        Ok(())
+    }
+
+    fn set_timer(&mut self, ms: u64, context: Vec<u8>) {
+        self.timers.push((ms, context));
+    }
+
+    fn push(&mut self, channel_id: u32, message: &ProviderPush) -> anyhow::Result<()> {
+        self.pushes.push((channel_id, message.clone()));
+        Ok(())
+    }
+
+    fn http_respond(&mut self, status: http::StatusCode, body: Vec<u8>) -> anyhow::Result<()> {
+        self.http.push((status, serde_json::from_slice(&body)?));
+        Ok(())
+    }
+
+    fn save_state(&mut self, bytes: &[u8]) {
+        self.saved = Some(bytes.to_vec());
+    }
+}
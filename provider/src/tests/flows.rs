@@ -0,0 +1,412 @@
+use proptest::prelude::*;
+use kinode_process_lib::{http, SendErrorKind};
+
+use super::*;
+use crate::transport::{HttpRequest, Incoming};
+use crate::ui_messages::ProviderStateView;
+
+const STRANGER: &str = "stranger.os@coordinator:hapa:hapa.os";
+
+/// The state a tab last saw on `channel_id`.
+fn shown_state(h: &Harness, channel_id: u32) -> Option<ProviderStateView> {
+    h.transport.pushes_to(channel_id).iter().rev().find_map(|push| match push {
+        ProviderPush::StateUpdate(view) => Some(view.state.clone()),
+        _ => None,
+    })
+}
+
+fn sent_work(h: &Harness, channel_id: u32, job_id: &str) -> bool {
+    h.transport.pushes_to(channel_id).iter()
+        .any(|push| matches!(push, ProviderPush::WorkRequest(payload) if payload.id == job_id))
+}
+
+#[test]
+fn register_assign_progress_complete_kick() {
+    let mut h = Harness::new();
+    let session = h.connect(1);
+    h.ui(1, &session, "worker_capabilities", serde_json::json!({ "webgpu": true }));
+
+    h.register();
+    let (status, body) = h.transport.last_http();
+    assert_eq!(*status, http::StatusCode::OK);
+    assert_eq!(body["status"], "success");
+    assert_eq!(h.state.state, ProviderState::Idle);
+    assert_eq!(h.state.coordinator, Some(address(COORDINATOR)));
+
+    h.request_from(COORDINATOR, ProviderRequest::AssignWork(work_request("job-1")));
+    assert!(matches!(h.transport.last_response(), ProviderResponse::WorkAssigned));
+    assert!(sent_work(&h, 1, "job-1"));
+    assert_eq!(h.state.current_job_id(), Some("job-1"));
+
+    h.ui(1, &session, "progress_update", serde_json::json!({ "progress": 50 }));
+    assert!(matches!(h.state.state, ProviderState::Working { progress: Some(50), .. }));
+
+    h.ui(1, &session, "work_result", serde_json::json!(embedding()));
+    assert_eq!(h.state.state, ProviderState::Idle);
+    let report = h.transport.sent.last().expect("no report sent");
+    assert_eq!(report.target, address(COORDINATOR));
+    let ProviderResponse::WorkCompleted { result, .. } = serde_json::from_slice(&report.body).unwrap() else {
+        panic!("report is not a completion");
+    };
+    assert_eq!(result.id, "job-1");
+    // No encoding was negotiated, so the embeddings travel as JSON
+    assert_eq!(result.embeddings.len(), 512);
+    assert!(report.blob.is_none());
+
+    // The coordinator acknowledges the report
+    let context = report.ack.clone().expect("report without ack").1;
+    h.deliver(Incoming::Response {
+        source: address(COORDINATOR),
+        body: serde_json::to_vec(&CoordinatorResponse::Ack).unwrap(),
+        context: Some(context),
+    });
+    assert!(!h.state.outbox.contains("job-1"));
+
+    h.request_from(COORDINATOR, ProviderRequest::Kick);
+    assert_eq!(h.state.state, ProviderState::Unbound);
+    assert_eq!(h.state.coordinator, None);
+    assert_eq!(shown_state(&h, 1), Some(ProviderStateView::Unbound));
+}
+
+#[test]
+fn requests_from_other_nodes_are_refused() {
+    let mut h = Harness::new();
+    h.connect(1);
+    h.register();
+
+    h.request_from(STRANGER, ProviderRequest::Kick);
+    assert!(matches!(h.transport.last_response(), ProviderResponse::Error(_)));
+    assert_eq!(h.state.state, ProviderState::Idle);
+    assert_eq!(h.state.coordinator, Some(address(COORDINATOR)));
+}
+
+#[test]
+fn registration_with_an_unreachable_coordinator_fails() {
+    let mut h = Harness::new();
+    h.transport = FakeTransport::new(Box::new(|_, _| None));
+    h.connect(1);
+    h.register();
+
+    assert_eq!(h.transport.last_http().0, http::StatusCode::BAD_GATEWAY);
+    assert_eq!(h.state.state, ProviderState::Unbound);
+    assert_eq!(h.state.coordinator, None);
+}
+
+#[test]
+fn a_rejected_registration_is_an_error_and_leaves_the_provider_unbound() {
+    let mut h = Harness::new();
+    h.transport = FakeTransport::new(Box::new(|_, _| Some(CoordinatorResponse::RegistrationRejected {
+        reason: "no capacity".to_string(),
+    })));
+    h.connect(1);
+    h.register();
+
+    let (status, body) = h.transport.last_http();
+    assert_eq!(*status, http::StatusCode::FORBIDDEN);
+    assert_eq!(body["message"], "no capacity");
+    assert_eq!(h.state.state, ProviderState::Unbound);
+}
+
+#[test]
+fn coordinators_are_served_from_the_cache_and_refreshed_on_a_timer() {
+    let mut h = Harness::new();
+    h.register();
+    let calls = h.transport.calls.len();
+
+    h.deliver(Incoming::Http(HttpRequest {
+        path: "/coordinators".to_string(),
+        method: http::Method::GET,
+        query: [("refresh".to_string(), "true".to_string())].into(),
+        body: None,
+    }));
+    let (status, body) = h.transport.last_http();
+    assert_eq!(*status, http::StatusCode::OK);
+    assert_eq!(body[0]["address"], COORDINATOR);
+    assert_eq!(h.transport.calls.len(), calls);
+
+    let (delay, context) = h.transport.timers.pop().expect("no refresh scheduled");
+    assert_eq!(delay, 0);
+    h.deliver(Incoming::Response {
+        source: address("our.os@timer:distro:sys"),
+        body: Vec::new(),
+        context: Some(context),
+    });
+    // Only the periodic round schedules another
+    assert!(h.transport.timers.is_empty());
+    assert_eq!(h.transport.calls.len(), calls);
+
+    // Asked without waiting, the answer arrives as a response
+    let sent = h.transport.sent.last().expect("no GetPeers sent");
+    assert!(matches!(serde_json::from_slice(&sent.body), Ok(CoordinatorRequest::GetPeers)));
+    let (_, context) = sent.ack.clone().expect("GetPeers sent without a context");
+    h.deliver(Incoming::Response {
+        source: address(COORDINATOR),
+        body: serde_json::to_vec(&CoordinatorResponse::Peers(vec![CoordinatorInfo {
+            address: STRANGER.to_string(),
+            required_models: vec![MODEL.to_string()],
+        }])).unwrap(),
+        context: Some(context),
+    });
+    assert_eq!(h.state.coordinators.list(None).len(), 2);
+}
+
+#[test]
+fn a_coordinator_that_does_not_answer_discovery_is_forgotten() {
+    let mut h = Harness::new();
+    h.register();
+    h.deliver(Incoming::Response {
+        source: address("our.os@timer:distro:sys"),
+        body: Vec::new(),
+        context: Some(serde_json::to_vec(&PendingContext::RefreshCoordinators { periodic: false }).unwrap()),
+    });
+    let (_, context) = h.transport.sent.last().and_then(|sent| sent.ack.clone()).expect("no GetPeers sent");
+    h.deliver(Incoming::SendError { kind: SendErrorKind::Timeout, context: Some(context) });
+    assert!(h.state.coordinators.list(None).is_empty());
+}
+
+#[test]
+fn a_failure_streak_goes_offline_without_the_coordinator() {
+    let mut h = Harness::new();
+    let session = h.connect(1);
+    h.register();
+    // The coordinator stops answering
+    h.transport = FakeTransport::new(Box::new(|_, _| None));
+
+    let max = h.state.settings.recovery.max_consecutive_failures;
+    for job in 0..max {
+        h.request_from(COORDINATOR, ProviderRequest::AssignWork(work_request(&format!("job-{job}"))));
+        h.ui(1, &session, "work_failed", serde_json::json!({ "error": "out of memory" }));
+    }
+    assert_eq!(h.state.state, ProviderState::Offline);
+    assert_eq!(h.state.recovery.consecutive_failures, 0);
+    // Told without waiting for an answer
+    assert!(h.transport.calls.iter().all(|(_, request)| !matches!(request, CoordinatorRequest::GoOffline)));
+    assert!(h.transport.sent.iter().any(|sent| {
+        matches!(serde_json::from_slice(&sent.body), Ok(CoordinatorRequest::GoOffline))
+    }));
+}
+
+#[test]
+fn an_exhausted_provider_takes_no_new_work() {
+    let mut h = Harness::new();
+    h.connect(1);
+    h.register();
+    h.state.recovery.consecutive_failures = h.state.settings.recovery.max_consecutive_failures;
+
+    h.request_from(COORDINATOR, ProviderRequest::AssignWork(work_request("job-1")));
+    assert!(matches!(h.transport.last_response(), ProviderResponse::Busy(_)));
+    assert!(!sent_work(&h, 1, "job-1"));
+}
+
+#[test]
+fn a_push_is_rejected_only_when_it_is_invalid() {
+    let mut h = Harness::new();
+    let session = h.connect(1);
+    h.ui(1, &session, "go_offline", serde_json::Value::Null);
+    assert!(matches!(
+        h.transport.pushes_to(1).last(),
+        Some(ProviderPush::Error { message_type: Some(message_type), .. }) if message_type == "go_offline"
+    ));
+
+    h.register();
+    h.transport = FakeTransport::new(Box::new(|_, _| None));
+    h.ui(1, &session, "still_bound", serde_json::Value::Null);
+    // The coordinator timed out, which the tab could not have helped
+    assert!(h.transport.pushes_to(1).iter().all(|push| !matches!(push, ProviderPush::Error { .. })));
+    assert_eq!(h.state.state, ProviderState::Idle);
+}
+
+#[test]
+fn a_bad_setting_leaves_every_setting_unchanged() {
+    let mut h = Harness::new();
+    let before = serde_json::to_value(&h.state.settings).unwrap();
+    h.http(http::Method::POST, "/settings", serde_json::json!({
+        "recovery": { "max_consecutive_failures": 2, "backoff_base_ms": 100, "backoff_max_ms": 1000 },
+        "coordinator_allowlist": [COORDINATOR],
+        "max_queue_depth": 10_000,
+    }));
+
+    assert_eq!(h.transport.last_http().0, http::StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(serde_json::to_value(&h.state.settings).unwrap(), before);
+}
+
+#[test]
+fn only_the_assigned_channel_reports_on_a_job() {
+    let mut h = Harness::new();
+    let first = h.connect(1);
+    let second = h.connect(2);
+    h.register();
+    h.request_from(COORDINATOR, ProviderRequest::AssignWork(work_request("job-1")));
+    let (holder, holder_session, other, other_session) = match h.workers.holder("job-1") {
+        Some(1) => (1, first, 2, second),
+        _ => (2, second, 1, first),
+    };
+
+    h.ui(other, &other_session, "work_result", serde_json::json!(embedding()));
+    assert!(matches!(
+        h.transport.pushes_to(other).last(),
+        Some(ProviderPush::Error { message_type: Some(message_type), .. }) if message_type == "work_result"
+    ));
+    assert_eq!(h.state.current_job_id(), Some("job-1"));
+
+    h.ui(holder, &holder_session, "work_result", serde_json::json!(embedding()));
+    assert_eq!(h.state.state, ProviderState::Idle);
+}
+
+#[test]
+fn a_job_moves_to_another_worker_when_its_worker_leaves() {
+    let mut h = Harness::new();
+    h.connect(1);
+    h.connect(2);
+    h.register();
+    h.request_from(COORDINATOR, ProviderRequest::AssignWork(work_request("job-1")));
+    let holder = h.workers.holder("job-1").expect("job not assigned");
+    let other = if holder == 1 { 2 } else { 1 };
+
+    h.deliver(Incoming::WebSocketClose(holder));
+    assert_eq!(h.workers.holder("job-1"), Some(other));
+    assert!(sent_work(&h, other, "job-1"));
+}
+
+// Something a coordinator, a UI tab or the runtime may do next
+#[derive(Debug, Clone)]
+enum Step {
+    Register,
+    Connect(u32),
+    Close(u32),
+    Assign(u8),
+    AssignFromStranger(u8),
+    HealthPing,
+    Kick,
+    Progress(u32, u32),
+    Result(u32),
+    Failed(u32),
+    StillBound(u32),
+    GoOffline(u32),
+    FireTimer,
+    AckReports,
+}
+
+fn step() -> impl Strategy<Value = Step> {
+    let channel = 1..=3u32;
+    prop_oneof![
+        Just(Step::Register),
+        channel.clone().prop_map(Step::Connect),
+        channel.clone().prop_map(Step::Close),
+        (0..4u8).prop_map(Step::Assign),
+        (0..4u8).prop_map(Step::AssignFromStranger),
+        Just(Step::HealthPing),
+        Just(Step::Kick),
+        (channel.clone(), 0..=100u32).prop_map(|(c, p)| Step::Progress(c, p)),
+        channel.clone().prop_map(Step::Result),
+        channel.clone().prop_map(Step::Failed),
+        channel.clone().prop_map(Step::StillBound),
+        channel.prop_map(Step::GoOffline),
+        Just(Step::FireTimer),
+        Just(Step::AckReports),
+    ]
+}
+
+fn session(h: &Harness, channel_id: u32) -> String {
+    h.workers.list().iter()
+        .find(|w| w.channel_id == channel_id)
+        .map(|w| w.session_id.clone())
+        .unwrap_or_default()
+}
+
+fn run(h: &mut Harness, step: Step, acked: &mut usize) {
+    match step {
+        Step::Register => h.register(),
+        Step::Connect(channel_id) => {
+            h.connect(channel_id);
+        }
+        Step::Close(channel_id) => h.deliver(Incoming::WebSocketClose(channel_id)),
+        Step::Assign(job) => {
+            h.request_from(COORDINATOR, ProviderRequest::AssignWork(work_request(&format!("job-{job}"))));
+        }
+        Step::AssignFromStranger(job) => {
+            h.request_from(STRANGER, ProviderRequest::AssignWork(work_request(&format!("job-{job}"))));
+        }
+        Step::HealthPing => h.request_from(COORDINATOR, ProviderRequest::HealthPing),
+        Step::Kick => h.request_from(COORDINATOR, ProviderRequest::Kick),
+        Step::Progress(channel_id, progress) => {
+            let session = session(h, channel_id);
+            h.ui(channel_id, &session, "progress_update", serde_json::json!({ "progress": progress }));
+        }
+        Step::Result(channel_id) => {
+            let session = session(h, channel_id);
+            h.ui(channel_id, &session, "work_result", serde_json::json!(embedding()));
+        }
+        Step::Failed(channel_id) => {
+            let session = session(h, channel_id);
+            h.ui(channel_id, &session, "work_failed", serde_json::json!({ "error": "out of memory" }));
+        }
+        Step::StillBound(channel_id) => {
+            let session = session(h, channel_id);
+            h.ui(channel_id, &session, "still_bound", serde_json::Value::Null);
+        }
+        Step::GoOffline(channel_id) => {
+            let session = session(h, channel_id);
+            h.ui(channel_id, &session, "go_offline", serde_json::Value::Null);
+        }
+        Step::FireTimer => {
+            if !h.transport.timers.is_empty() {
+                let (_, context) = h.transport.timers.remove(0);
+                h.deliver(Incoming::Response {
+                    source: address("our.os@timer:distro:sys"),
+                    body: Vec::new(),
+                    context: Some(context),
+                });
+            }
+        }
+        Step::AckReports => {
+            let contexts: Vec<Vec<u8>> = h.transport.sent[*acked..].iter()
+                .filter_map(|sent| sent.ack.as_ref().map(|(_, context)| context.clone()))
+                .collect();
+            *acked = h.transport.sent.len();
+            for context in contexts {
+                h.deliver(Incoming::Response {
+                    source: address(COORDINATOR),
+                    body: serde_json::to_vec(&CoordinatorResponse::Ack).unwrap(),
+                    context: Some(context),
+                });
+            }
+        }
+    }
+}
+
+/// Requests `step` sends that must be answered exactly once.
+fn expected_responses(h: &Harness, step: &Step) -> usize {
+    match step {
+        Step::Assign(_) | Step::HealthPing => 1,
+        Step::AssignFromStranger(_) => 1,
+        Step::Kick => usize::from(!h.state.accepts_requests_from(&address(COORDINATOR))),
+        _ => 0,
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn handlers_keep_their_invariants(steps in prop::collection::vec(step(), 1..40)) {
+        let mut h = Harness::new();
+        let mut acked = 0;
+        for step in steps {
+            let responses = h.transport.responses.len() + expected_responses(&h, &step);
+            run(&mut h, step.clone(), &mut acked);
+
+            prop_assert_eq!(h.transport.responses.len(), responses, "answers after {:?}", step);
+            prop_assert!(h.state.job_queue.len() <= h.state.settings.max_queue_depth);
+            if h.state.state == ProviderState::Unbound {
+                prop_assert!(h.state.coordinator.is_none());
+                prop_assert!(h.state.job_queue.is_empty());
+            }
+            // Every tab sees the state the provider is in
+            let current = ProviderStateView::of(&h.state.state);
+            for channel_id in h.workers.channel_ids() {
+                prop_assert_eq!(shown_state(&h, channel_id), Some(current.clone()));
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use super::*;
+use crate::journal::{self, JobOutcome, JournalEntry};
+
+fn lines(entries: &[JournalEntry]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for entry in entries {
+        bytes.extend(serde_json::to_vec(entry).unwrap());
+        bytes.push(b'\n');
+    }
+    bytes
+}
+
+fn completed(id: &str) -> JobOutcome {
+    JobOutcome::Completed(WorkResult {
+        id: id.to_string(),
+        embeddings: vec![1.0],
+        space: None,
+        timestamp: 0,
+    })
+}
+
+#[test]
+fn fold_keeps_one_record_per_job_in_assignment_order() {
+    let records = journal::fold(&lines(&[
+        JournalEntry::Assigned(Assignment::Single(work_request("a"))),
+        JournalEntry::Assigned(Assignment::Single(work_request("b"))),
+        JournalEntry::Progress { job_id: "a".to_string(), progress: 10 },
+        JournalEntry::Progress { job_id: "a".to_string(), progress: 60 },
+        JournalEntry::Finished(completed("b")),
+        JournalEntry::Reported { job_id: "b".to_string() },
+    ]));
+
+    assert_eq!(records.iter().map(|r| r.id()).collect::<Vec<_>>(), ["a", "b"]);
+    assert_eq!(records[0].progress, Some(60));
+    assert!(records[0].outcome.is_none());
+    assert!(!records[0].is_settled());
+    assert!(records[1].is_settled());
+}
+
+#[test]
+fn a_reassigned_job_starts_over() {
+    let records = journal::fold(&lines(&[
+        JournalEntry::Assigned(Assignment::Single(work_request("a"))),
+        JournalEntry::Progress { job_id: "a".to_string(), progress: 90 },
+        JournalEntry::Finished(completed("a")),
+        JournalEntry::Assigned(Assignment::Single(work_request("a"))),
+    ]));
+
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].progress, None);
+    assert!(records[0].outcome.is_none());
+}
+
+#[test]
+fn torn_and_unknown_lines_are_skipped() {
+    let mut bytes = lines(&[JournalEntry::Assigned(Assignment::Single(work_request("a")))]);
+    bytes.extend(b"{\"Progress\":{\"job_id\":\"a\",\"prog");
+    bytes.push(b'\n');
+    bytes.extend(lines(&[
+        // Entries about jobs the journal never saw assigned
+        JournalEntry::Progress { job_id: "x".to_string(), progress: 5 },
+        JournalEntry::Finished(completed("x")),
+    ]));
+
+    let records = journal::fold(&bytes);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].id(), "a");
+    assert_eq!(records[0].progress, None);
+}
+
+#[test]
+fn an_abandoned_report_settles_the_job() {
+    let records = journal::fold(&lines(&[
+        JournalEntry::Assigned(Assignment::Single(work_request("a"))),
+        JournalEntry::Finished(completed("a")),
+        JournalEntry::Abandoned { job_id: "a".to_string() },
+    ]));
+
+    assert!(records[0].abandoned);
+    assert!(!records[0].reported);
+    assert!(records[0].is_settled());
+}
+
+#[test]
+fn compaction_rebuilds_the_same_records() {
+    let records = journal::fold(&lines(&[
+        JournalEntry::Assigned(Assignment::Single(work_request("a"))),
+        JournalEntry::Progress { job_id: "a".to_string(), progress: 40 },
+        JournalEntry::Assigned(Assignment::Single(work_request("b"))),
+        JournalEntry::Finished(completed("b")),
+        JournalEntry::Abandoned { job_id: "b".to_string() },
+    ]));
+
+    let compacted = journal::fold(&journal::encode(&records).unwrap());
+    assert_eq!(compacted.len(), records.len());
+    for (before, after) in records.iter().zip(&compacted) {
+        assert_eq!(before.assignment, after.assignment);
+        assert_eq!(before.progress, after.progress);
+        assert_eq!(before.outcome.is_some(), after.outcome.is_some());
+        assert_eq!(before.reported, after.reported);
+        assert_eq!(before.abandoned, after.abandoned);
+    }
+}
@@ -0,0 +1,113 @@
+use proptest::prelude::*;
+
+use super::*;
+use crate::machine::{EventKind, StateKind};
+
+fn job_id(job: u8) -> String {
+    format!("job-{job}")
+}
+
+fn work_error(job: u8) -> WorkError {
+    WorkError {
+        id: job_id(job),
+        error: "failed".to_string(),
+        kind: WorkErrorKind::Other,
+        timestamp: 0,
+    }
+}
+
+// Few job ids, so events often are and often are not about the current job
+fn event() -> impl Strategy<Value = ProviderEvent> {
+    let job = 0..3u8;
+    prop_oneof![
+        Just(ProviderEvent::RegisterWithCoordinator(address(COORDINATOR))),
+        job.clone().prop_map(|job| ProviderEvent::StartWork(work_request(&job_id(job)))),
+        job.clone().prop_map(|job| ProviderEvent::StartBatch(WorkBatch {
+            id: job_id(job),
+            model: MODEL.to_string(),
+            items: Vec::new(),
+            timestamp: 0,
+        })),
+        job.clone().prop_map(|job| ProviderEvent::CompleteWork(WorkResult {
+            id: job_id(job),
+            embeddings: Vec::new(),
+            space: None,
+            timestamp: 0,
+        })),
+        job.clone().prop_map(|job| ProviderEvent::CompleteBatch(BatchResult {
+            batch_id: job_id(job),
+            results: Vec::new(),
+            timestamp: 0,
+        })),
+        job.clone().prop_map(|job| ProviderEvent::FailWork { error: work_error(job) }),
+        job.prop_map(|job| ProviderEvent::TimeOut { error: work_error(job) }),
+        (0..=100u32).prop_map(ProviderEvent::UpdateProgress),
+        Just(ProviderEvent::Kicked),
+        Just(ProviderEvent::GoOffline),
+        Just(ProviderEvent::GoOnline(address(COORDINATOR))),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn transitions_follow_the_table(events in prop::collection::vec(event(), 1..60)) {
+        let mut state = State::new();
+        for event in events {
+            let before = state.state.clone();
+            let coordinator = state.coordinator.clone();
+            let job = state.current_job_id().map(str::to_string);
+            let allowed = state.allows(&event);
+            state.unpublished = false;
+
+            let audited = state.audit.recent(usize::MAX, false).len();
+            let result = state.transition(event.clone());
+
+            // What allows promises is what transition does
+            prop_assert_eq!(result.is_ok(), allowed, "{:?} in {:?}", event, before);
+            if result.is_ok() && matches!(event, ProviderEvent::UpdateProgress(_)) {
+                // Accepted progress ticks are not audited
+                prop_assert_eq!(state.audit.recent(usize::MAX, false).len(), audited);
+                continue;
+            }
+            let entry = state.audit.recent(1, false)[0].clone();
+            prop_assert_eq!(entry.event, EventKind::of(&event));
+            prop_assert_eq!(entry.from, StateKind::of(&before));
+            if result.is_err() {
+                prop_assert_eq!(&state.state, &before);
+                prop_assert_eq!(&state.coordinator, &coordinator);
+                prop_assert!(entry.rejected.is_some());
+                prop_assert!(!state.unpublished);
+                continue;
+            }
+            prop_assert_eq!(entry.to, Some(StateKind::of(&state.state)));
+            prop_assert!(state.unpublished);
+
+            // A job is only ever started by assigning it
+            if state.current_job_id().is_some() && state.current_job_id() != job.as_deref() {
+                prop_assert!(matches!(event, ProviderEvent::StartWork(_) | ProviderEvent::StartBatch(_)));
+            }
+            if let ProviderEvent::Kicked = event {
+                prop_assert_eq!(&state.state, &ProviderState::Unbound);
+                prop_assert!(state.coordinator.is_none());
+                prop_assert!(state.job_queue.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn the_audit_log_chains(events in prop::collection::vec(event(), 1..60)) {
+        let mut state = State::new();
+        for event in events {
+            let _ = state.transition(event);
+        }
+        let accepted: Vec<_> = state.audit.recent(usize::MAX, false).into_iter()
+            .filter(|entry| entry.rejected.is_none())
+            .collect();
+        for pair in accepted.windows(2) {
+            prop_assert_eq!(pair[0].to, Some(pair[1].from));
+        }
+        if let Some(last) = accepted.last() {
+            prop_assert_eq!(last.to, Some(StateKind::of(&state.state)));
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Provider tests on a plain host: the handlers run against `FakeTransport`
+//! instead of the Kinode runtime. Run them from the workspace root with
+//! `cargo test -p provider`, no node or kit build needed.
+
+mod fake;
+mod flows;
+mod journal;
+mod machine;
+mod outbox;
+mod persist;
+mod recovery;
+mod validation;
+
+use kinode_process_lib::{Address, http, http::server::WsMessageType};
+
+use crate::structs::*;
+use crate::transport::{HttpRequest, Incoming};
+use crate::ui_messages::ProviderPush;
+use crate::workers::WorkerPool;
+use fake::FakeTransport;
+
+pub const COORDINATOR: &str = "coordinator.os@coordinator:hapa:hapa.os";
+pub const MODEL: &str = "clip-vit-base-patch16";
+
+pub fn address(address: &str) -> Address {
+    address.parse().expect("invalid address")
+}
+
+pub fn work_request(id: &str) -> WorkRequest {
+    WorkRequest {
+        id: id.to_string(),
+        model: MODEL.to_string(),
+        uri: String::new(),
+        input: Some(WorkInput::Text { text: "a photo of a cat".to_string() }),
+        timeout_ms: None,
+        timestamp: 0,
+    }
+}
+
+/// A unit vector of the default model's dimension.
+pub fn embedding() -> Vec<f64> {
+    let mut embedding = vec![0.0; 512];
+    embedding[0] = 1.0;
+    embedding
+}
+
+/// A provider with its transport, driven one message at a time the way
+/// init's loop drives it.
+pub struct Harness {
+    pub state: State,
+    pub workers: WorkerPool,
+    pub transport: FakeTransport,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        Self {
+            state: State::new(),
+            workers: WorkerPool::new(),
+            transport: FakeTransport::accepting(),
+        }
+    }
+
+    /// Handle `incoming` and publish the result.
+    pub fn deliver(&mut self, incoming: Incoming) {
+        self.transport.incoming.push_back(incoming);
+        crate::handle_next(&mut self.state, &mut self.workers, &mut self.transport);
+    }
+
+    pub fn http(&mut self, method: http::Method, path: &str, body: serde_json::Value) {
+        self.deliver(Incoming::Http(HttpRequest {
+            path: path.to_string(),
+            method,
+            query: Default::default(),
+            body: Some(serde_json::to_vec(&body).unwrap()),
+        }));
+    }
+
+    /// Open a UI channel and return the session it was given.
+    pub fn connect(&mut self, channel_id: u32) -> String {
+        self.deliver(Incoming::WebSocketOpen(channel_id));
+        self.transport.pushes_to(channel_id).iter()
+            .find_map(|push| match push {
+                ProviderPush::Session { session_id, .. } => Some(session_id.clone()),
+                _ => None,
+            })
+            .expect("no session pushed")
+    }
+
+    /// A text frame from a UI tab, `message_type` and `data` as the UI sends them.
+    pub fn ui(&mut self, channel_id: u32, session_id: &str, message_type: &str, data: serde_json::Value) {
+        let body = serde_json::json!({
+            "message_type": message_type,
+            "data": data,
+            "session_id": session_id,
+        });
+        self.deliver(Incoming::WebSocketPush {
+            channel_id,
+            message_type: WsMessageType::Text,
+            bytes: Some(serde_json::to_vec(&body).unwrap()),
+        });
+    }
+
+    pub fn request_from(&mut self, source: &str, request: ProviderRequest) {
+        self.deliver(Incoming::Request {
+            source: address(source),
+            body: serde_json::to_vec(&request).unwrap(),
+            expects_response: true,
+        });
+    }
+
+    pub fn register(&mut self) {
+        self.http(http::Method::POST, "/register_provider", serde_json::json!({
+            "coordinator_address": COORDINATOR,
+        }));
+    }
+}
@@ -0,0 +1,74 @@
+use super::*;
+use crate::journal::JobOutcome;
+use crate::outbox::Outbox;
+
+fn failed(id: &str, error: &str) -> JobOutcome {
+    JobOutcome::Failed(WorkError {
+        id: id.to_string(),
+        error: error.to_string(),
+        kind: WorkErrorKind::Other,
+        timestamp: 0,
+    })
+}
+
+#[test]
+fn a_newer_outcome_replaces_the_queued_one() {
+    let mut outbox = Outbox::new();
+    outbox.push(address(COORDINATOR), failed("a", "first"));
+    outbox.push(address(COORDINATOR), failed("b", "other"));
+    outbox.get_mut("a").unwrap().attempts = 3;
+    outbox.push(address(COORDINATOR), failed("a", "second"));
+
+    assert_eq!(outbox.job_ids(), ["b", "a"]);
+    let delivery = outbox.get("a").unwrap();
+    assert_eq!(delivery.attempts, 0);
+    assert!(matches!(&delivery.outcome, JobOutcome::Failed(error) if error.error == "second"));
+}
+
+#[test]
+fn removed_deliveries_are_gone() {
+    let mut outbox = Outbox::new();
+    outbox.push(address(COORDINATOR), failed("a", "error"));
+    assert!(outbox.remove("a").is_some());
+    assert!(outbox.remove("a").is_none());
+    assert!(!outbox.contains("a"));
+}
+
+#[test]
+fn retries_back_off_up_to_a_minute() {
+    let mut outbox = Outbox::new();
+    outbox.push(address(COORDINATOR), failed("a", "error"));
+    let delivery = outbox.get_mut("a").unwrap();
+    let delays: Vec<u64> = [1, 2, 3, 7, 40]
+        .into_iter()
+        .map(|attempts| {
+            delivery.attempts = attempts;
+            delivery.retry_delay_ms()
+        })
+        .collect();
+    assert_eq!(delays, [1_000, 2_000, 4_000, 60_000, 60_000]);
+}
+
+#[test]
+fn the_outbox_survives_a_round_trip() {
+    let mut outbox = Outbox::new();
+    outbox.push(address(COORDINATOR), failed("a", "error"));
+    let decoded: Outbox = serde_json::from_value(serde_json::to_value(&outbox).unwrap()).unwrap();
+    assert_eq!(decoded.job_ids(), ["a"]);
+    assert_eq!(decoded.get("a").unwrap().coordinator, address(COORDINATOR));
+}
+
+#[test]
+fn abandoned_deliveries_are_kept_for_the_ui_but_not_resent() {
+    let mut outbox = Outbox::new();
+    outbox.push(address(COORDINATOR), failed("a", "error"));
+    assert!(outbox.abandon("a"));
+    assert!(!outbox.abandon("a"));
+
+    assert!(!outbox.contains("a"));
+    assert!(outbox.job_ids().is_empty());
+    // What the UI lists
+    let abandoned = &serde_json::to_value(&outbox).unwrap()["abandoned"];
+    assert_eq!(abandoned[0]["job_id"], "a");
+    assert_eq!(abandoned.as_array().unwrap().len(), 1);
+}
@@ -0,0 +1,95 @@
+use super::*;
+use crate::persist::{self, legacy, STATE_VERSION};
+
+fn envelope(version: u32, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = b"HPST".to_vec();
+    bytes.extend(version.to_le_bytes());
+    bytes.extend(payload);
+    bytes
+}
+
+fn legacy_bytes(state: legacy::ProviderState, coordinator: Option<&str>) -> Vec<u8> {
+    bincode::serialize(&legacy::State {
+        state,
+        coordinator: coordinator.map(address),
+        supported_models: vec![MODEL.to_string()],
+    })
+    .unwrap()
+}
+
+#[test]
+fn a_state_survives_a_round_trip() {
+    let mut state = State::new();
+    state.state = ProviderState::Working { request: work_request("job-1"), progress: Some(30) };
+    state.coordinator = Some(address(COORDINATOR));
+    state.job_queue.push_back(Assignment::Single(work_request("job-2")));
+    state.settings.max_queue_depth = 9;
+    state.recovery.consecutive_failures = 2;
+
+    let bytes = persist::encode(&state).unwrap();
+    assert_eq!(&bytes[..8], &envelope(STATE_VERSION, &[])[..]);
+    let decoded = persist::decode(&bytes).unwrap();
+    assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&state).unwrap());
+}
+
+#[test]
+fn fields_added_with_a_default_need_no_migration() {
+    let mut value = serde_json::to_value(State::new()).unwrap();
+    value.as_object_mut().unwrap().remove("outbox");
+    let decoded = persist::decode(&envelope(STATE_VERSION, &serde_json::to_vec(&value).unwrap())).unwrap();
+    assert!(decoded.outbox.job_ids().is_empty());
+}
+
+#[test]
+fn unreadable_envelopes_are_refused() {
+    let payload = serde_json::to_vec(&State::new()).unwrap();
+    assert!(persist::decode(&envelope(0, &payload)).is_err());
+    assert!(persist::decode(&envelope(STATE_VERSION + 1, &payload)).is_err());
+    assert!(persist::decode(b"HPST\x01").is_err());
+    assert!(persist::decode(&envelope(STATE_VERSION, b"{\"state\":")).is_err());
+}
+
+#[test]
+fn a_legacy_binding_is_kept_at_version_0() {
+    let state = persist::decode(&legacy_bytes(legacy::ProviderState::Idle, Some(COORDINATOR))).unwrap();
+    assert_eq!(state.state, ProviderState::Idle);
+    assert_eq!(state.coordinator, Some(address(COORDINATOR)));
+    assert_eq!(state.protocol_version, 0);
+    assert!(state.models.get(MODEL).is_some());
+}
+
+#[test]
+fn a_legacy_job_in_flight_is_dropped() {
+    let working = legacy::ProviderState::Working {
+        request: legacy::WorkRequest {
+            id: "job-1".to_string(),
+            model: MODEL.to_string(),
+            uri: "https://example.com/cat.png".to_string(),
+            timestamp: 0,
+        },
+        progress: Some(50),
+    };
+    let state = persist::decode(&legacy_bytes(working, Some(COORDINATOR))).unwrap();
+    assert_eq!(state.state, ProviderState::Idle);
+    assert_eq!(state.current_job_id(), None);
+}
+
+#[test]
+fn a_legacy_failure_keeps_its_error() {
+    let failed = legacy::ProviderState::Failed {
+        error: legacy::WorkError { id: "job-1".to_string(), error: "out of memory".to_string(), timestamp: 7 },
+    };
+    let state = persist::decode(&legacy_bytes(failed, Some(COORDINATOR))).unwrap();
+    let ProviderState::Failed { error } = state.state else {
+        panic!("legacy failure became {:?}", state.state);
+    };
+    assert_eq!((error.id.as_str(), error.error.as_str(), error.timestamp), ("job-1", "out of memory", 7));
+    assert_eq!(error.kind, WorkErrorKind::Other);
+}
+
+#[test]
+fn a_legacy_state_without_a_coordinator_is_unbound() {
+    let state = persist::decode(&legacy_bytes(legacy::ProviderState::Idle, None)).unwrap();
+    assert_eq!(state.state, ProviderState::Unbound);
+    assert_eq!(state.protocol_version, protocol::PROTOCOL_VERSION);
+}
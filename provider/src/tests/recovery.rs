@@ -0,0 +1,55 @@
+use crate::recovery::{Recovery, RecoverySettings};
+
+fn settings() -> RecoverySettings {
+    RecoverySettings {
+        max_consecutive_failures: 3,
+        backoff_base_ms: 1_000,
+        backoff_max_ms: 10_000,
+    }
+}
+
+#[test]
+fn backoff_doubles_up_to_the_cap() {
+    let mut recovery = Recovery::new();
+    let delays: Vec<u64> = (0..6)
+        .map(|attempt| {
+            recovery.attempt = attempt;
+            recovery.backoff_ms(&settings())
+        })
+        .collect();
+    assert_eq!(delays, [1_000, 2_000, 4_000, 8_000, 10_000, 10_000]);
+}
+
+#[test]
+fn backoff_does_not_overflow() {
+    let settings = RecoverySettings {
+        backoff_max_ms: u64::MAX,
+        backoff_base_ms: u64::MAX / 2,
+        ..settings()
+    };
+    let recovery = Recovery { attempt: u32::MAX, ..Recovery::new() };
+    assert_eq!(recovery.backoff_ms(&settings), u64::MAX);
+}
+
+#[test]
+fn the_streak_counts_failures_in_a_row() {
+    let mut recovery = Recovery::new();
+    recovery.job_failed(Some("model"));
+    recovery.job_failed(None);
+    assert!(!recovery.exhausted(&settings()));
+    assert_eq!(recovery.model.as_deref(), Some("model"));
+
+    recovery.job_succeeded();
+    for _ in 0..3 {
+        recovery.job_failed(None);
+    }
+    assert!(recovery.exhausted(&settings()));
+}
+
+#[test]
+fn settings_are_validated() {
+    assert!(settings().validate().is_ok());
+    assert!(RecoverySettings { max_consecutive_failures: 0, ..settings() }.validate().is_err());
+    assert!(RecoverySettings { backoff_base_ms: 0, ..settings() }.validate().is_err());
+    assert!(RecoverySettings { backoff_base_ms: 20_000, ..settings() }.validate().is_err());
+}
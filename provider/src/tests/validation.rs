@@ -0,0 +1,131 @@
+use super::*;
+use crate::validation::{decode_embedding_frame, validate_embeddings};
+
+fn spec(dimension: u32, normalization: Normalization) -> ModelSpec {
+    ModelSpec {
+        id: MODEL.to_string(),
+        revision: "test".to_string(),
+        dimension,
+        modalities: Vec::new(),
+        quantization: Quantization::Fp32,
+        normalization,
+        expected_runtime_ms: 0,
+    }
+}
+
+fn values(values: &[f64]) -> Vec<serde_json::Value> {
+    values.iter().map(|v| serde_json::json!(v)).collect()
+}
+
+/// A vector of `dimension` values with L2 norm `norm`.
+fn with_norm(dimension: usize, norm: f64) -> Vec<serde_json::Value> {
+    let mut embedding = vec![0.0; dimension];
+    embedding[0] = norm;
+    values(&embedding)
+}
+
+#[test]
+fn a_unit_vector_of_the_right_dimension_passes() {
+    let embeddings = validate_embeddings(&with_norm(4, 1.0), Some(&spec(4, Normalization::L2))).unwrap();
+    assert_eq!(embeddings, [1.0, 0.0, 0.0, 0.0]);
+}
+
+#[test]
+fn the_dimension_must_match_the_spec() {
+    assert_eq!(
+        validate_embeddings(&with_norm(3, 1.0), Some(&spec(4, Normalization::L2))),
+        Err(EmbeddingViolation::Dimension { expected: 4, actual: 3 }),
+    );
+    assert_eq!(
+        validate_embeddings(&[], Some(&spec(4, Normalization::None))),
+        Err(EmbeddingViolation::Dimension { expected: 4, actual: 0 }),
+    );
+}
+
+#[test]
+fn values_must_be_finite_numbers() {
+    let mut embedding = values(&[0.5, 0.5]);
+    embedding.push(serde_json::json!("0.5"));
+    assert_eq!(validate_embeddings(&embedding, None), Err(EmbeddingViolation::Malformed { index: 2, reason: None }));
+
+    // JSON has no NaN, but a value can overflow f32
+    assert_eq!(
+        validate_embeddings(&values(&[0.0, 1e39]), None),
+        Err(EmbeddingViolation::NonFinite { index: 1 }),
+    );
+    assert_eq!(
+        validate_embeddings(&values(&[-1e39]), None),
+        Err(EmbeddingViolation::NonFinite { index: 0 }),
+    );
+}
+
+#[test]
+fn the_norm_may_deviate_within_the_tolerance() {
+    let spec = spec(4, Normalization::L2);
+    assert!(validate_embeddings(&with_norm(4, 1.009), Some(&spec)).is_ok());
+    assert!(validate_embeddings(&with_norm(4, 0.991), Some(&spec)).is_ok());
+    assert!(matches!(
+        validate_embeddings(&with_norm(4, 1.02), Some(&spec)),
+        Err(EmbeddingViolation::NotNormalized { .. })
+    ));
+    assert!(matches!(
+        validate_embeddings(&with_norm(4, 0.0), Some(&spec)),
+        Err(EmbeddingViolation::NotNormalized { .. })
+    ));
+}
+
+#[test]
+fn unnormalized_models_skip_the_norm_check() {
+    assert!(validate_embeddings(&with_norm(4, 12.0), Some(&spec(4, Normalization::None))).is_ok());
+    // Without a spec only the values are checked
+    assert!(validate_embeddings(&with_norm(7, 12.0), None).is_ok());
+}
+
+/// A binary work_result frame as the UI builds it.
+fn frame(tag: u8, encoding: EmbeddingEncoding, values: &[f32]) -> Vec<u8> {
+    let (header, bytes) = encoding.encode(values);
+    let mut frame = vec![tag];
+    frame.extend(header.scale.to_le_bytes());
+    frame.extend(bytes);
+    frame
+}
+
+#[test]
+fn frames_decode_in_every_encoding() {
+    let spec = spec(4, Normalization::L2);
+    for (tag, encoding) in [(0, EmbeddingEncoding::F32Le), (1, EmbeddingEncoding::F16Le), (2, EmbeddingEncoding::Int8)] {
+        let embeddings = decode_embedding_frame(&frame(tag, encoding, &[0.6, 0.8, 0.0, 0.0]), Some(&spec))
+            .unwrap_or_else(|e| panic!("{encoding:?}: {e}"));
+        assert_eq!(embeddings.len(), 4);
+    }
+}
+
+#[test]
+fn decoded_frames_are_checked_against_the_spec() {
+    assert_eq!(
+        decode_embedding_frame(&frame(0, EmbeddingEncoding::F32Le, &[1.0, 0.0]), Some(&spec(4, Normalization::L2))),
+        Err(EmbeddingViolation::Dimension { expected: 4, actual: 2 }),
+    );
+    assert_eq!(
+        decode_embedding_frame(&frame(0, EmbeddingEncoding::F32Le, &[1.0, f32::NAN]), None),
+        Err(EmbeddingViolation::NonFinite { index: 1 }),
+    );
+    // Overflows half precision
+    assert_eq!(
+        decode_embedding_frame(&frame(1, EmbeddingEncoding::F16Le, &[1e6, 0.0]), None),
+        Err(EmbeddingViolation::NonFinite { index: 0 }),
+    );
+}
+
+#[test]
+fn broken_frames_say_what_is_wrong() {
+    let reason = |frame: &[u8]| match decode_embedding_frame(frame, None) {
+        Err(EmbeddingViolation::Malformed { reason: Some(reason), .. }) => reason,
+        other => panic!("frame was not refused as malformed: {other:?}"),
+    };
+    assert!(reason(&[0, 0, 0]).contains("no header"));
+    assert!(reason(&frame(9, EmbeddingEncoding::F32Le, &[1.0])).contains("unknown encoding"));
+    let mut torn = frame(0, EmbeddingEncoding::F32Le, &[1.0]);
+    torn.pop();
+    assert!(reason(&torn).contains("do not fit"));
+}
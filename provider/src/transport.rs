@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use kinode_process_lib::{
+    await_message, get_blob, timer,
+    Address, LazyLoadBlob, Message, Request, Response, SendErrorKind,
+    http::{
+        self,
+        server::{HttpServerRequest, WsMessageType},
+    },
+};
+
+use crate::ui_messages::ProviderPush;
+
+/// A message for the provider, decoded from what the runtime delivered.
+#[derive(Debug, Clone)]
+pub enum Incoming {
+    // From another process, normally a coordinator
+    Request {
+        source: Address,
+        body: Vec<u8>,
+        expects_response: bool,
+    },
+    // The answer to a request we did not wait on, or one of our timers
+    Response {
+        source: Address,
+        body: Vec<u8>,
+        context: Option<Vec<u8>>,
+    },
+    // A request we did not wait on got no answer
+    SendError {
+        kind: SendErrorKind,
+        context: Option<Vec<u8>>,
+    },
+    Http(HttpRequest),
+    WebSocketOpen(u32),
+    WebSocketClose(u32),
+    WebSocketPush {
+        channel_id: u32,
+        message_type: WsMessageType,
+        bytes: Option<Vec<u8>>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    // Bound path, e.g. "/settings"
+    pub path: String,
+    pub method: http::Method,
+    pub query: HashMap<String, String>,
+    pub body: Option<Vec<u8>>,
+}
+
+/// Everything the provider exchanges with the outside world. The handlers
+/// only talk through this, so they run the same against the Kinode runtime
+/// and against the in-memory fake the tests use.
+pub trait Transport {
+    /// Block until the next message arrives.
+    fn receive(&mut self) -> anyhow::Result<Incoming>;
+
+    /// Answer the request being handled.
+    fn respond(&mut self, body: Vec<u8>) -> anyhow::Result<()>;
+
+    /// Send a request and block for the answer. The inner error is set when
+    /// `target` did not answer in time.
+    fn call(
+        &mut self,
+        target: &Address,
+        body: Vec<u8>,
+        timeout_secs: u64,
+    ) -> anyhow::Result<Result<Vec<u8>, SendErrorKind>>;
+
+    /// Send a request without waiting. With `ack` set to a timeout and a
+    /// context, the answer or its absence comes back later as an
+    /// `Incoming::Response` or `Incoming::SendError` carrying the context.
+    fn send(
+        &mut self,
+        target: &Address,
+        body: Vec<u8>,
+        blob: Option<LazyLoadBlob>,
+        ack: Option<(u64, Vec<u8>)>,
+    ) -> anyhow::Result<()>;
+
+    /// Deliver `context` as an `Incoming::Response` after `ms`.
+    fn set_timer(&mut self, ms: u64, context: Vec<u8>);
+
+    /// Send a text frame to a UI channel.
+    fn push(&mut self, channel_id: u32, message: &ProviderPush) -> anyhow::Result<()>;
+
+    /// Answer the HTTP request being handled with a JSON body.
+    fn http_respond(&mut self, status: http::StatusCode, body: Vec<u8>) -> anyhow::Result<()>;
+
+    fn save_state(&mut self, bytes: &[u8]);
+}
+
+/// The transport of a provider running as a Kinode process.
+pub struct KinodeTransport {
+    our: Address,
+}
+
+impl KinodeTransport {
+    pub fn new(our: Address) -> Self {
+        Self { our }
+    }
+
+    fn decode_http(message: &Message) -> anyhow::Result<Incoming> {
+        Ok(match serde_json::from_slice(message.body())? {
+            HttpServerRequest::Http(req) => Incoming::Http(HttpRequest {
+                path: req.path()?,
+                method: req.method()?,
+                query: req.query_params().clone(),
+                body: get_blob().map(|blob| blob.bytes),
+            }),
+            HttpServerRequest::WebSocketOpen { channel_id, .. } => Incoming::WebSocketOpen(channel_id),
+            HttpServerRequest::WebSocketClose(channel_id) => Incoming::WebSocketClose(channel_id),
+            HttpServerRequest::WebSocketPush { channel_id, message_type } => Incoming::WebSocketPush {
+                channel_id,
+                message_type,
+                bytes: get_blob().map(|blob| blob.bytes),
+            },
+            _ => return Err(anyhow::anyhow!("unknown http server request: {:?}", message.body())),
+        })
+    }
+}
+
+impl Transport for KinodeTransport {
+    fn receive(&mut self) -> anyhow::Result<Incoming> {
+        let message = loop {
+            let message = match await_message() {
+                Ok(message) => message,
+                Err(send_error) => return Ok(Incoming::SendError {
+                    kind: send_error.kind().clone(),
+                    context: send_error.context().map(<[u8]>::to_vec),
+                }),
+            };
+            if message.source().process != "http_server:distro:sys" {
+                break message;
+            }
+            // Only our own http_server speaks for the bound paths, another
+            // node's would get around their authentication
+            if message.source().node != self.our.node {
+                kiprintln!("dropping http_server message from {}", message.source());
+                continue;
+            }
+            return Self::decode_http(&message);
+        };
+        Ok(if message.is_request() {
+            Incoming::Request {
+                source: message.source().clone(),
+                body: message.body().to_vec(),
+                expects_response: matches!(message, Message::Request { expects_response: Some(_), .. }),
+            }
+        } else {
+            Incoming::Response {
+                source: message.source().clone(),
+                body: message.body().to_vec(),
+                context: message.context().map(<[u8]>::to_vec),
+            }
+        })
+    }
+
+    fn respond(&mut self, body: Vec<u8>) -> anyhow::Result<()> {
+        Response::new().body(body).send()
+    }
+
+    fn call(
+        &mut self,
+        target: &Address,
+        body: Vec<u8>,
+        timeout_secs: u64,
+    ) -> anyhow::Result<Result<Vec<u8>, SendErrorKind>> {
+        Ok(Request::to(target)
+            .body(body)
+            .send_and_await_response(timeout_secs)?
+            .map(|response| response.body().to_vec())
+            .map_err(|e| e.kind().clone()))
+    }
+
+    fn send(
+        &mut self,
+        target: &Address,
+        body: Vec<u8>,
+        blob: Option<LazyLoadBlob>,
+        ack: Option<(u64, Vec<u8>)>,
+    ) -> anyhow::Result<()> {
+        let mut request = Request::to(target).body(body);
+        if let Some(blob) = blob {
+            request = request.blob(blob);
+        }
+        if let Some((timeout_secs, context)) = ack {
+            request = request.context(context).expects_response(timeout_secs);
+        }
+        request.send()
+    }
+
+    fn set_timer(&mut self, ms: u64, context: Vec<u8>) {
+        timer::set_timer(ms, Some(context));
+    }
+
+    fn push(&mut self, channel_id: u32, message: &ProviderPush) -> anyhow::Result<()> {
+        http::server::send_ws_push(
+            channel_id,
+            WsMessageType::Text,
+            LazyLoadBlob {
+                mime: Some("application/json".to_string()),
+                bytes: serde_json::to_vec(message)?,
+            },
+        );
+        Ok(())
+    }
+
+    fn http_respond(&mut self, status: http::StatusCode, body: Vec<u8>) -> anyhow::Result<()> {
+        http::server::send_response(
+            status,
+            Some(HashMap::from([(
+                String::from("Content-Type"),
+                String::from("application/json"),
+            )])),
+            body,
+        );
+        Ok(())
+    }
+
+    fn save_state(&mut self, bytes: &[u8]) {
+        kinode_process_lib::set_state(bytes);
+    }
+}
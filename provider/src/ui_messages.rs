@@ -0,0 +1,369 @@
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use kinode_process_lib::Address;
+
+use crate::outbox::Delivery;
+use crate::structs::{BatchItem, Modality, ProviderState, State, WorkBatch, WorkInput, WorkRequest};
+use crate::transport::Transport;
+
+// A text frame from a UI tab. Binary frames carry a bare work result, see
+// validation::decode_embedding_frame
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct WebSocketMessage {
+    #[serde(flatten)]
+    pub message: UiMessage,
+    // Session the provider gave this channel on connect
+    #[serde(default)]
+    pub session_id: Option<String>,
+}
+
+/// Everything a UI tab may send the provider, as
+/// `{"message_type": ..., "data": ...}`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "message_type", content = "data", rename_all = "snake_case")]
+pub enum UiMessage {
+    // The embedding of the current job. Kept as raw values so a malformed
+    // one fails the job instead of the message
+    WorkResult(#[schemars(with = "Vec<Option<f64>>")] Vec<serde_json::Value>),
+    WorkFailed {
+        #[serde(default)]
+        error: Option<String>,
+    },
+    BatchResult {
+        batch_id: String,
+        results: Vec<BatchItemReport>,
+    },
+    ProgressUpdate {
+        progress: u32,
+    },
+    ChallengeResult {
+        challenge_id: String,
+        model: String,
+        embeddings: Vec<f64>,
+    },
+    ChallengeFailed {
+        #[serde(default)]
+        error: Option<String>,
+    },
+    // Ask the coordinator whether we are still bound and go online if so
+    StillBound,
+    GoOffline,
+    ModelStatus {
+        model: String,
+        loaded: bool,
+        warm: bool,
+    },
+    WorkerCapabilities {
+        webgpu: bool,
+    },
+}
+
+impl UiMessage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            UiMessage::WorkResult(_) => "work_result",
+            UiMessage::WorkFailed { .. } => "work_failed",
+            UiMessage::BatchResult { .. } => "batch_result",
+            UiMessage::ProgressUpdate { .. } => "progress_update",
+            UiMessage::ChallengeResult { .. } => "challenge_result",
+            UiMessage::ChallengeFailed { .. } => "challenge_failed",
+            UiMessage::StillBound => "still_bound",
+            UiMessage::GoOffline => "go_offline",
+            UiMessage::ModelStatus { .. } => "model_status",
+            UiMessage::WorkerCapabilities { .. } => "worker_capabilities",
+        }
+    }
+
+    /// Whether only the channel holding the current job may send it.
+    pub fn reports_on_job(&self) -> bool {
+        matches!(
+            self,
+            UiMessage::WorkResult(_)
+                | UiMessage::WorkFailed { .. }
+                | UiMessage::BatchResult { .. }
+                | UiMessage::ProgressUpdate { .. }
+        )
+    }
+}
+
+// One item of a batch_result, either embeddings or an error
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BatchItemReport {
+    pub id: String,
+    #[serde(default)]
+    #[schemars(with = "Option<Vec<Option<f64>>>")]
+    pub embeddings: Option<Vec<serde_json::Value>>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Everything the provider pushes to UI tabs, as `{"type": ..., "data": ...}`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum ProviderPush {
+    // First message on every channel, later pushes must carry the session
+    Session {
+        session_id: String,
+        channel_id: u32,
+    },
+    StateUpdate(StateView),
+    WorkRequest(WorkPayload),
+    BatchRequest {
+        id: String,
+        model: String,
+        items: Vec<BatchItemPayload>,
+        timestamp: u64,
+    },
+    // Stop computing a job, anything sent for it is ignored
+    CancelWork {
+        id: String,
+    },
+    WarmModel {
+        model: String,
+    },
+    ChallengeRequest {
+        challenge_id: String,
+        model: String,
+        uri: String,
+    },
+    // Outcome of a registration, the same body /register_provider answers with
+    RegistrationResult(RegistrationResult),
+    // A message from this channel was rejected
+    Error {
+        message: String,
+        // The rejected message's type, None if it did not parse
+        message_type: Option<String>,
+    },
+}
+
+impl ProviderPush {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProviderPush::Session { .. } => "session",
+            ProviderPush::StateUpdate(_) => "state_update",
+            ProviderPush::WorkRequest(_) => "work_request",
+            ProviderPush::BatchRequest { .. } => "batch_request",
+            ProviderPush::CancelWork { .. } => "cancel_work",
+            ProviderPush::WarmModel { .. } => "warm_model",
+            ProviderPush::ChallengeRequest { .. } => "challenge_request",
+            ProviderPush::RegistrationResult(_) => "registration_result",
+            ProviderPush::Error { .. } => "error",
+        }
+    }
+
+    pub fn work(work_request: &WorkRequest) -> Self {
+        ProviderPush::WorkRequest(WorkPayload::of(work_request))
+    }
+
+    pub fn batch(batch: &WorkBatch, items: &[BatchItem]) -> Self {
+        ProviderPush::BatchRequest {
+            id: batch.id.clone(),
+            model: batch.model.clone(),
+            items: items.iter()
+                .map(|item| BatchItemPayload {
+                    id: item.id.clone(),
+                    uri: item.uri.clone(),
+                    input: item.input(),
+                    modality: item.input().modality(),
+                })
+                .collect(),
+            timestamp: batch.timestamp,
+        }
+    }
+}
+
+/// What a tab is shown of the provider's state.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StateView {
+    pub state: ProviderStateView,
+    pub coordinator: Option<String>,
+    pub supported_models: Vec<String>,
+    // Reports the coordinator has not acknowledged yet
+    pub pending_deliveries: Vec<DeliveryView>,
+    // Reports the provider stopped resending, the coordinator never got them
+    pub abandoned_deliveries: Vec<DeliveryView>,
+}
+
+impl StateView {
+    pub fn of(state: &State) -> Self {
+        StateView {
+            state: ProviderStateView::of(&state.state),
+            coordinator: state.coordinator.as_ref().map(|addr| addr.to_string()),
+            supported_models: state.models.ids(),
+            pending_deliveries: state.outbox.deliveries().iter().map(DeliveryView::of).collect(),
+            abandoned_deliveries: state.outbox.abandoned().iter().map(DeliveryView::of).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum ProviderStateView {
+    Unbound,
+    Idle,
+    Offline,
+    Working {
+        request: WorkPayload,
+        progress: Option<u32>,
+    },
+    WorkingBatch {
+        id: String,
+        model: String,
+        items: usize,
+        progress: Option<u32>,
+    },
+    Failed {
+        error: FailureView,
+    },
+}
+
+impl ProviderStateView {
+    pub fn of(state: &ProviderState) -> Self {
+        match state {
+            ProviderState::Unbound => ProviderStateView::Unbound,
+            ProviderState::Idle => ProviderStateView::Idle,
+            ProviderState::Offline => ProviderStateView::Offline,
+            ProviderState::Working { request, progress } => ProviderStateView::Working {
+                request: WorkPayload::of(request),
+                progress: *progress,
+            },
+            ProviderState::WorkingBatch { batch, progress } => ProviderStateView::WorkingBatch {
+                id: batch.id.clone(),
+                model: batch.model.clone(),
+                items: batch.items.len(),
+                progress: *progress,
+            },
+            ProviderState::Failed { error } => ProviderStateView::Failed {
+                error: FailureView {
+                    id: error.id.clone(),
+                    error: error.error.clone(),
+                    timestamp: error.timestamp,
+                },
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FailureView {
+    pub id: String,
+    pub error: String,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DeliveryView {
+    pub job_id: String,
+    pub coordinator: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+impl DeliveryView {
+    fn of(delivery: &Delivery) -> Self {
+        DeliveryView {
+            job_id: delivery.job_id.clone(),
+            coordinator: delivery.coordinator.to_string(),
+            attempts: delivery.attempts,
+            last_error: delivery.last_error.clone(),
+        }
+    }
+}
+
+/// Outcome of a registration, pending while the UI answers a challenge.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RegistrationResult {
+    pub status: RegistrationStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coordinator: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_models: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationStatus {
+    Success,
+    Pending,
+    Error,
+}
+
+impl RegistrationResult {
+    pub fn success(coordinator: &Address, required_models: Vec<String>) -> Self {
+        RegistrationResult {
+            status: RegistrationStatus::Success,
+            coordinator: Some(coordinator.to_string()),
+            message: None,
+            required_models,
+        }
+    }
+
+    pub fn pending(coordinator: &Address, message: &str) -> Self {
+        RegistrationResult {
+            status: RegistrationStatus::Pending,
+            coordinator: Some(coordinator.to_string()),
+            message: Some(message.to_string()),
+            required_models: Vec::new(),
+        }
+    }
+
+    pub fn error(message: String) -> Self {
+        RegistrationResult {
+            status: RegistrationStatus::Error,
+            coordinator: None,
+            message: Some(message),
+            required_models: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct WorkPayload {
+    pub id: String,
+    pub uri: String,
+    pub input: WorkInput,
+    pub modality: Modality,
+    pub model: String,
+    pub timestamp: u64,
+}
+
+impl WorkPayload {
+    pub fn of(work_request: &WorkRequest) -> Self {
+        WorkPayload {
+            id: work_request.id.clone(),
+            uri: work_request.uri.clone(),
+            input: work_request.input(),
+            modality: work_request.input().modality(),
+            model: work_request.model.clone(),
+            timestamp: work_request.timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BatchItemPayload {
+    pub id: String,
+    pub uri: String,
+    pub input: WorkInput,
+    pub modality: Modality,
+}
+
+pub fn push_to_channel(
+    transport: &mut dyn Transport,
+    channel_id: u32,
+    message: &ProviderPush,
+) -> anyhow::Result<()> {
+    kiprintln!("Sending {} to channel {}", message.name(), channel_id);
+    transport.push(channel_id, message)
+}
+
+/// JSON Schemas of both directions, served at /schema for checking UIs and
+/// third-party compute clients.
+pub fn schema() -> serde_json::Value {
+    serde_json::json!({
+        "ui_to_provider": schemars::schema_for!(WebSocketMessage),
+        "provider_to_ui": schemars::schema_for!(ProviderPush),
+    })
+}
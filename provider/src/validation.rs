@@ -0,0 +1,88 @@
+use crate::structs::{
+    EmbeddingEncoding, EmbeddingViolation, EncodedEmbeddings, ModelSpec, Normalization,
+};
+
+/// Allowed deviation of the L2 norm from 1 for normalized models. Loose
+/// enough for fp16 and int8 outputs.
+const NORM_TOLERANCE: f32 = 0.01;
+
+/// Parse a worker's embedding and check it against what `spec` promises.
+/// Without a spec only the values themselves are checked.
+pub fn validate_embeddings(
+    values: &[serde_json::Value],
+    spec: Option<&ModelSpec>,
+) -> Result<Vec<f32>, EmbeddingViolation> {
+    let embeddings = values.iter()
+        .enumerate()
+        .map(|(index, value)| {
+            let value = value.as_f64().ok_or(EmbeddingViolation::Malformed { index, reason: None })? as f32;
+            if !value.is_finite() {
+                return Err(EmbeddingViolation::NonFinite { index });
+            }
+            Ok(value)
+        })
+        .collect::<Result<Vec<f32>, _>>()?;
+
+    check_embeddings(&embeddings, spec)?;
+    Ok(embeddings)
+}
+
+/// Check already decoded values against what `spec` promises.
+pub fn check_embeddings(
+    embeddings: &[f32],
+    spec: Option<&ModelSpec>,
+) -> Result<(), EmbeddingViolation> {
+    if let Some(index) = embeddings.iter().position(|v| !v.is_finite()) {
+        return Err(EmbeddingViolation::NonFinite { index });
+    }
+    let Some(spec) = spec else {
+        return Ok(());
+    };
+
+    if embeddings.len() != spec.dimension as usize {
+        return Err(EmbeddingViolation::Dimension {
+            expected: spec.dimension,
+            actual: embeddings.len() as u32,
+        });
+    }
+    if spec.normalization == Normalization::L2 {
+        let norm = embeddings.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if (norm - 1.0).abs() > NORM_TOLERANCE {
+            return Err(EmbeddingViolation::NotNormalized { norm });
+        }
+    }
+    Ok(())
+}
+
+/// Decode a binary `work_result` frame from the UI and check it against
+/// `spec`. Layout: one encoding byte (0 F32Le, 1 F16Le, 2 Int8), the Int8
+/// scale as a little-endian f32, then the values.
+pub fn decode_embedding_frame(
+    frame: &[u8],
+    spec: Option<&ModelSpec>,
+) -> Result<Vec<f32>, EmbeddingViolation> {
+    let malformed = |reason: String| EmbeddingViolation::Malformed { index: 0, reason: Some(reason) };
+
+    if frame.len() < 5 {
+        return Err(malformed(format!("frame of {} bytes has no header", frame.len())));
+    }
+    let encoding = match frame[0] {
+        0 => EmbeddingEncoding::F32Le,
+        1 => EmbeddingEncoding::F16Le,
+        2 => EmbeddingEncoding::Int8,
+        tag => return Err(malformed(format!("unknown encoding {}", tag))),
+    };
+    let payload = &frame[5..];
+    if !payload.len().is_multiple_of(encoding.bytes_per_value()) {
+        return Err(malformed(format!("{} payload bytes do not fit {:?}", payload.len(), encoding)));
+    }
+    let encoded = EncodedEmbeddings {
+        encoding,
+        dimension: (payload.len() / encoding.bytes_per_value()) as u32,
+        scale: f32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]),
+    };
+    let embeddings = encoded.decode(payload).map_err(malformed)?;
+
+    check_embeddings(&embeddings, spec)?;
+    Ok(embeddings)
+}
@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How long a job whose worker disconnected waits for a tab to reconnect
+/// before it is reported as failed.
+pub const RECONNECT_GRACE_MS: u64 = 15_000;
+
+/// What a connected UI tab is currently doing.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum WorkerStatus {
+    Idle,
+    Busy { job_id: String },
+}
+
+/// A connected UI tab able to compute embeddings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Worker {
+    pub channel_id: u32,
+    // Handed to the tab on connect, every text push must carry it
+    pub session_id: String,
+    pub status: WorkerStatus,
+    // None until the tab reports its capabilities
+    pub webgpu: Option<bool>,
+    pub jobs_assigned: u64,
+}
+
+impl Worker {
+    /// Tabs that have not reported yet are given the benefit of the doubt.
+    pub fn can_compute(&self) -> bool {
+        self.webgpu != Some(false)
+    }
+}
+
+/// The websocket channels of connected UI tabs. The provider runs one job
+/// at a time, so the pool only decides which tab computes it, moving on to
+/// another tab from one job to the next. Load is not spread across tabs.
+/// Lives only in memory: channels do not survive a restart.
+#[derive(Debug, Default)]
+pub struct WorkerPool {
+    workers: BTreeMap<u32, Worker>,
+    sessions_started: u64,
+}
+
+impl WorkerPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a worker for a new channel and return its session id.
+    pub fn connect(&mut self, channel_id: u32) -> String {
+        self.sessions_started += 1;
+        let started_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        // Unique across restarts, the channel id alone may be reused
+        let session_id = format!("{:x}-{:x}-{:x}", started_ms, channel_id, self.sessions_started);
+        self.workers.insert(channel_id, Worker {
+            channel_id,
+            session_id: session_id.clone(),
+            status: WorkerStatus::Idle,
+            webgpu: None,
+            jobs_assigned: 0,
+        });
+        session_id
+    }
+
+    /// Remove a worker, returning it so a job it held can be recovered.
+    pub fn disconnect(&mut self, channel_id: u32) -> Option<Worker> {
+        self.workers.remove(&channel_id)
+    }
+
+    pub fn contains(&self, channel_id: u32) -> bool {
+        self.workers.contains_key(&channel_id)
+    }
+
+    /// Whether a push on `channel_id` carries that channel's session.
+    pub fn session_matches(&self, channel_id: u32, session_id: Option<&str>) -> bool {
+        self.workers.get(&channel_id)
+            .is_some_and(|w| Some(w.session_id.as_str()) == session_id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn channel_ids(&self) -> Vec<u32> {
+        self.workers.keys().copied().collect()
+    }
+
+    pub fn list(&self) -> Vec<&Worker> {
+        self.workers.values().collect()
+    }
+
+    pub fn has_capable(&self) -> bool {
+        self.workers.values().any(Worker::can_compute)
+    }
+
+    pub fn set_webgpu(&mut self, channel_id: u32, webgpu: bool) {
+        if let Some(worker) = self.workers.get_mut(&channel_id) {
+            worker.webgpu = Some(webgpu);
+        }
+    }
+
+    /// The tab for the next job: idle and capable, preferring one that
+    /// reported WebGPU, then the one given the fewest jobs so far.
+    pub fn pick(&self) -> Option<u32> {
+        self.workers.values()
+            .filter(|w| w.status == WorkerStatus::Idle && w.can_compute())
+            .min_by_key(|w| (w.webgpu.is_none(), w.jobs_assigned))
+            .map(|w| w.channel_id)
+    }
+
+    pub fn assign(&mut self, channel_id: u32, job_id: &str) {
+        if let Some(worker) = self.workers.get_mut(&channel_id) {
+            worker.status = WorkerStatus::Busy { job_id: job_id.to_string() };
+            worker.jobs_assigned += 1;
+        }
+    }
+
+    /// Free workers still marked busy with a job other than `job_id`, which
+    /// the provider is no longer running.
+    pub fn release_except(&mut self, job_id: Option<&str>) {
+        for worker in self.workers.values_mut() {
+            if matches!(&worker.status, WorkerStatus::Busy { job_id: held } if Some(held.as_str()) != job_id) {
+                worker.status = WorkerStatus::Idle;
+            }
+        }
+    }
+
+    pub fn holder(&self, job_id: &str) -> Option<u32> {
+        self.workers.values()
+            .find(|w| matches!(&w.status, WorkerStatus::Busy { job_id: held } if held == job_id))
+            .map(|w| w.channel_id)
+    }
+}
@@ -0,0 +1,40 @@
+// Writes ../pkg/challenges.json, the reference embeddings the coordinator
+// checks registering providers against. Computed the way embeddings.ts does
+// it, on the CPU instead of WebGPU. Run with `npm run challenges` whenever a
+// model or the reference images change, the coordinator refuses providers
+// for any model without a reference.
+import { writeFileSync } from 'fs';
+import { CLIPVisionModel, AutoProcessor, RawImage } from '@huggingface/transformers';
+
+const MODELS = {
+  'clip-vit-base-patch16': 'Xenova/clip-vit-base-patch16',
+};
+
+const IMAGES = [
+  'https://huggingface.co/datasets/Xenova/transformers.js-docs/resolve/main/cats.jpg',
+  'https://huggingface.co/datasets/Xenova/transformers.js-docs/resolve/main/tiger.jpg',
+  'https://huggingface.co/datasets/Xenova/transformers.js-docs/resolve/main/football-match.jpg',
+];
+
+// Room for the difference between WebGPU and CPU results
+const TOLERANCE = 0.02;
+
+const references = [];
+for (const [model, modelId] of Object.entries(MODELS)) {
+  const config = { revision: 'main', format: 'onnx', quantized: false };
+  const visionModel = await CLIPVisionModel.from_pretrained(modelId, config);
+  const processor = await AutoProcessor.from_pretrained(modelId, config);
+
+  for (const uri of IMAGES) {
+    const image = await RawImage.fromURL(uri);
+    const { image_embeds } = await visionModel(await processor(image));
+    const normalized = image_embeds.normalize();
+    references.push({ model, uri, embeddings: normalized.tolist()[0], tolerance: TOLERANCE });
+    image_embeds.dispose();
+    normalized.dispose();
+    console.log(`${model}: ${uri}`);
+  }
+}
+
+writeFileSync(new URL('../../pkg/challenges.json', import.meta.url), JSON.stringify(references) + '\n');
+console.log(`wrote ${references.length} references`);
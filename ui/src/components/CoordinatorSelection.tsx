@@ -62,7 +62,7 @@ export function CoordinatorSelection({ onCoordinatorSelected }: {
             const registrationResult = await response.json();
             
             // Check registration response
-            if (registrationResult.status === 'success') {
+            if (registrationResult.status === 'success' || registrationResult.status === 'pending') {
                 setSelectedCoordinator(coordinator.address);
                 onCoordinatorSelected();
             } else {
@@ -1,12 +1,13 @@
 import { useState, useEffect, useRef } from 'react';
 import KinodeApi from '@kinode/client-api';
 import { PROVIDER_PROCESS_NAME } from '../utils/urls';
-import { getImageEmbeddings } from '../embeddings';
+import { getImageEmbeddings, getInputEmbeddings, initializeModels, WorkInput } from '../embeddings';
 
 interface WorkRequest {
   id: string;
   model: string;
   uri: string;
+  input?: WorkInput;
   timestamp: number;
 }
 
@@ -32,15 +33,33 @@ type ProviderState =
       request: WorkRequest;
       progress?: number;
     }
+  | {
+      type: 'WorkingBatch';
+      id: string;
+      model: string;
+      items: number;
+      progress?: number;
+    }
   | { 
       type: 'Failed';
       error: WorkError;
     };
 
+// A result the coordinator has not acknowledged yet
+interface PendingDelivery {
+  job_id: string;
+  coordinator: string;
+  attempts: number;
+  last_error: string | null;
+}
+
 interface State {
   state: ProviderState;
   coordinator: string | null;
   supported_models: string[];
+  pending_deliveries: PendingDelivery[];
+  // Results the provider stopped resending, the coordinator never got them
+  abandoned_deliveries: PendingDelivery[];
 }
 
 export function ProviderDashboard() {
@@ -50,11 +69,18 @@ export function ProviderDashboard() {
   const [state, setState] = useState<State>({
     state: { type: 'Unbound' },
     coordinator: null,
-    supported_models: ["clip-vit-base-patch16"]
+    supported_models: ["clip-vit-base-patch16"],
+    pending_deliveries: [],
+    abandoned_deliveries: []
   });
   //@ts-ignore
   const [api, setApi] = useState<KinodeApi | null>(null);
   const apiRef = useRef<KinodeApi | null>(null);
+  // Jobs the provider gave up on, their results would be stale
+  const cancelledJobsRef = useRef<Set<string>>(new Set());
+  // Session the provider assigned to this tab's channel, sent with every message
+  const sessionRef = useRef<string | null>(null);
+  const webgpuRef = useRef(false);
   const [jobStats, setJobStats] = useState({ totalJobs: 0, lastJobTime: null as string | null });
 
 
@@ -70,6 +96,7 @@ export function ProviderDashboard() {
         if (!adapter) throw new Error('No WebGPU adapter found');
         
         const device = await adapter.requestDevice();
+        webgpuRef.current = !!device;
 
         setHardwareStatus('ready');
 
@@ -90,12 +117,8 @@ export function ProviderDashboard() {
           },
           onOpen: () => {
             setHardwareStatus('ready');
-            apiInstance.send({
-              data: {
-                message_type: 'still_bound',
-                data: "lol"
-              }
-            });
+            // Nothing is sent until the provider hands out our session
+            sessionRef.current = null;
           },
           onClose: () => {
           },
@@ -124,6 +147,43 @@ export function ProviderDashboard() {
     };
   }, []);
 
+  // Let the provider know the model is loaded and has run at least once
+  const reportModelWarm = (model: string) => {
+    apiRef.current?.send({
+      data: {
+        message_type: 'model_status',
+        session_id: sessionRef.current,
+        data: { model, loaded: true, warm: true }
+      }
+    });
+  };
+
+  // Binary work_result frame: encoding byte (0 = f32 LE), f32 LE scale
+  // (unused for f32), then the values. A quarter of the size of JSON text.
+  const encodeEmbeddingFrame = (embeddings: number[]): ArrayBuffer => {
+    const buffer = new ArrayBuffer(5 + embeddings.length * 4);
+    const view = new DataView(buffer);
+    view.setUint8(0, 0);
+    view.setFloat32(1, 0, true);
+    embeddings.forEach((value, index) => view.setFloat32(5 + index * 4, value, true));
+    return buffer;
+  };
+
+  const sendWorkResult = (embeddings: number[]) => {
+    const socket: WebSocket | undefined = (apiRef.current as any)?.ws;
+    if (socket?.readyState === WebSocket.OPEN) {
+      socket.send(encodeEmbeddingFrame(embeddings));
+      return;
+    }
+    apiRef.current?.send({
+      data: {
+        message_type: 'work_result',
+        session_id: sessionRef.current,
+        data: embeddings
+      }
+    });
+  };
+
   // Handle incoming messages from provider process
   const handleMessage = async (event: any) => {
     //console.log('[ProviderDashboard] Processing message:', {
@@ -134,38 +194,32 @@ export function ProviderDashboard() {
     const message = typeof event === 'string' ? JSON.parse(event) : event;
 
     switch (message.type) {
-      case 'state_update':
-        //console.log('[ProviderDashboard] State update received:', {
-        //  message,
-        //  data: message.data, // Check if data exists
-        //  type: message.type,
-        //  state: message.state,
-        //  coordinator: message.coordinator,
-        //  fullMessage: JSON.stringify(message, null, 2) // Full message structure
-        //});
-        const stateString = message.state.state;
-        const transformedState = {
-          type: stateString,  // This converts "Idle" to { type: "Idle" }
-          ...(stateString === 'Working' ? { request: message.state.request } : {}),
-          ...(stateString === 'Failed' ? { error: message.state.error } : {})
-        };
-  
-        setState(prevState => ({
-          ...prevState,
-          state: transformedState,
-          coordinator: message.coordinator ?? prevState.coordinator
-        }));
-
-        // Validate the incoming state matches our type expectations
-        if (!message.state?.type) {
-          break;
-        }
+      case 'session':
+        sessionRef.current = message.data.session_id;
+        // Each tab is a worker; the provider only routes jobs to tabs that can compute
+        apiRef.current?.send({
+          data: {
+            message_type: 'worker_capabilities',
+            session_id: sessionRef.current,
+            data: { webgpu: webgpuRef.current }
+          }
+        });
+        apiRef.current?.send({
+          data: {
+            message_type: 'still_bound',
+            session_id: sessionRef.current
+          }
+        });
+        break;
 
+      case 'state_update':
+        // The provider sends the whole view, see StateView in /schema
+        setState(message.data);
         break;
 
       case 'work_request':
-        if (!message.data?.uri) {
-          console.warn('Invalid work request - missing URI');
+        if (!message.data?.input && !message.data?.uri) {
+          console.warn('Invalid work request - missing input');
           break;
         }
         
@@ -175,14 +229,15 @@ export function ProviderDashboard() {
         }
 
         try {
-          const embeddings = await getImageEmbeddings(message.data.uri);
+          const embeddings = await getInputEmbeddings(
+            message.data.input ?? { ImageUri: { uri: message.data.uri } }
+          );
+          if (cancelledJobsRef.current.delete(message.data.id)) {
+            break;
+          }
           
-          apiRef.current?.send({
-            data: {
-              message_type: 'work_result',
-              data: embeddings
-            }
-          });
+          sendWorkResult(embeddings);
+          reportModelWarm(message.data.model);
 
           setJobStats(prev => ({
             totalJobs: prev.totalJobs + 1,
@@ -191,10 +246,14 @@ export function ProviderDashboard() {
 
         } catch (error: any) {
           console.error('Work processing error:', error);
+          if (cancelledJobsRef.current.delete(message.data.id)) {
+            break;
+          }
           if (apiRef.current) {
             apiRef.current.send({
               data: {
                 message_type: 'work_failed',
+                session_id: sessionRef.current,
                 data: { error: error.message }
               }
             });
@@ -202,6 +261,113 @@ export function ProviderDashboard() {
         }
         break;
 
+      case 'batch_request': {
+        // Items run one after another on the warm model; a failing item
+        // is reported on its own and does not sink the rest of the batch
+        const items: { id: string; uri: string; input?: WorkInput }[] = message.data?.items ?? [];
+        const results = [];
+        for (const [index, item] of items.entries()) {
+          try {
+            const embeddings = await getInputEmbeddings(item.input ?? { ImageUri: { uri: item.uri } });
+            results.push({ id: item.id, embeddings });
+          } catch (error: any) {
+            console.error(`Batch item ${item.id} failed:`, error);
+            results.push({ id: item.id, error: error.message });
+          }
+          apiRef.current?.send({
+            data: {
+              message_type: 'progress_update',
+              session_id: sessionRef.current,
+              data: { progress: Math.round(((index + 1) / items.length) * 100) }
+            }
+          });
+        }
+
+        if (cancelledJobsRef.current.delete(message.data.id)) {
+          break;
+        }
+        apiRef.current?.send({
+          data: {
+            message_type: 'batch_result',
+            session_id: sessionRef.current,
+            data: { batch_id: message.data.id, results }
+          }
+        });
+        reportModelWarm(message.data.model);
+
+        setJobStats(prev => ({
+          totalJobs: prev.totalJobs + items.length,
+          lastJobTime: new Date().toLocaleTimeString()
+        }));
+        break;
+      }
+
+      case 'warm_model':
+        // Sent while the provider recovers from a failed job
+        try {
+          await initializeModels();
+          reportModelWarm(message.data.model);
+        } catch (error: any) {
+          console.error('Model warm-up failed:', error);
+        }
+        break;
+
+      case 'cancel_work':
+        // The job timed out, drop its result when it finishes
+        if (message.data?.id) {
+          cancelledJobsRef.current.add(message.data.id);
+        }
+        break;
+
+      case 'challenge_request':
+        // Coordinator asks us to prove we can run the model before registering
+        try {
+          const embeddings = await getImageEmbeddings(message.data.uri);
+          apiRef.current?.send({
+            data: {
+              message_type: 'challenge_result',
+              session_id: sessionRef.current,
+              data: {
+                challenge_id: message.data.challenge_id,
+                model: message.data.model,
+                embeddings
+              }
+            }
+          });
+          reportModelWarm(message.data.model);
+        } catch (error: any) {
+          console.error('Challenge processing error:', error);
+          apiRef.current?.send({
+            data: {
+              message_type: 'challenge_failed',
+              session_id: sessionRef.current,
+              data: { error: error.message }
+            }
+          });
+        }
+        break;
+
+      case 'registration_result':
+        if (message.data?.status !== 'success') {
+          setState(prevState => ({
+            ...prevState,
+            state: {
+              type: 'Failed',
+              error: {
+                id: '',
+                error: message.data?.message ?? 'Registration failed',
+                timestamp: Date.now()
+              }
+            }
+          }));
+        }
+        break;
+
+      case 'error':
+        // The provider rejected something we sent, see /schema for the message format
+        console.error(`Provider rejected ${message.data?.message_type ?? 'message'}:`, message.data?.message);
+        break;
+
       default:
         console.warn('Unknown message type:', message.type);
     }
@@ -219,13 +385,22 @@ export function ProviderDashboard() {
       if (!response.ok) {
         throw new Error(await response.text());
       }
+
+      const registrationResult = await response.json();
+      if (registrationResult.status === 'pending') {
+        // Registration completes once the model challenge is answered
+        return;
+      }
+      if (registrationResult.status !== 'success') {
+        throw new Error(registrationResult.message);
+      }
   
       
       // Send still_bound message and wait for state update from backend
       apiRef.current?.send({
         data: {
           message_type: 'still_bound',
-          data: coordinatorAddress // Pass coordinator address
+          session_id: sessionRef.current
         }
       });
       
@@ -263,7 +438,7 @@ export function ProviderDashboard() {
               apiRef.current?.send({
                 data: {
                   message_type: 'go_offline',
-                  data: "lol"
+                  session_id: sessionRef.current
                 }
               });
               //setState({ ProviderState: 'Offline', coordinator: state.coordinator ?? '' });
@@ -289,7 +464,7 @@ export function ProviderDashboard() {
               apiRef.current?.send({
                 data: {
                   message_type: 'still_bound',
-                  data: "lol"
+                  session_id: sessionRef.current
                 }
               });
             }}
@@ -313,7 +488,7 @@ export function ProviderDashboard() {
             apiRef.current?.send({
               data: {
                 message_type: 'still_bound',
-                data: "lol"
+                session_id: sessionRef.current
               }
             });
           }}
@@ -533,6 +708,32 @@ export function ProviderDashboard() {
                 {jobStats.lastJobTime && (
                   <p>Last job completed: {jobStats.lastJobTime}</p>
                 )}
+                {state.pending_deliveries.length > 0 && (
+                  <div style={{ marginTop: '0.5rem' }}>
+                    <p>Results awaiting coordinator acknowledgement: {state.pending_deliveries.length}</p>
+                    <ul style={{ marginTop: '0.25rem', paddingLeft: '1rem' }}>
+                      {state.pending_deliveries.map(delivery => (
+                        <li key={delivery.job_id}>
+                          {delivery.job_id} ({delivery.attempts} attempts
+                          {delivery.last_error ? `, last error: ${delivery.last_error}` : ''})
+                        </li>
+                      ))}
+                    </ul>
+                  </div>
+                )}
+                {state.abandoned_deliveries.length > 0 && (
+                  <div style={{ marginTop: '0.5rem', color: '#B91C1C' }}>
+                    <p>Results never acknowledged, no longer resent: {state.abandoned_deliveries.length}</p>
+                    <ul style={{ marginTop: '0.25rem', paddingLeft: '1rem' }}>
+                      {state.abandoned_deliveries.map(delivery => (
+                        <li key={delivery.job_id}>
+                          {delivery.job_id} ({delivery.attempts} attempts
+                          {delivery.last_error ? `, last error: ${delivery.last_error}` : ''})
+                        </li>
+                      ))}
+                    </ul>
+                  </div>
+                )}
               </div>
             </div>
           )}
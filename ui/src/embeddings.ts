@@ -1,7 +1,9 @@
 // hardcoded for clip-vit-base-patch16
 import { 
     CLIPVisionModel,
+    CLIPTextModelWithProjection,
     AutoProcessor,
+    AutoTokenizer,
     RawImage 
   } from '@huggingface/transformers';
   
@@ -35,9 +37,13 @@ import {
 
   type ProcessorType = Awaited<ReturnType<typeof AutoProcessor.from_pretrained>>;
   type VisionModelType = Awaited<ReturnType<typeof CLIPVisionModel.from_pretrained>>;
+  type TokenizerType = Awaited<ReturnType<typeof AutoTokenizer.from_pretrained>>;
+  type TextModelType = Awaited<ReturnType<typeof CLIPTextModelWithProjection.from_pretrained>>;
   
   let visionModelInstance: VisionModelType | null = null;
   let processorInstance: ProcessorType | null = null;
+  let tokenizerInstance: TokenizerType | null = null;
+  let textModelInstance: TextModelType | null = null;
 
   const MODEL_ID = "Xenova/clip-vit-base-patch16";
   const MODEL_CONFIG = {
@@ -64,6 +70,19 @@ export const initializeModels = async () => {
     }
   };
   
+// The text tower is only loaded once a text job arrives
+const initializeTextModels = async () => {
+    if (!textModelInstance) {
+      textModelInstance = await CLIPTextModelWithProjection.from_pretrained(MODEL_ID, {
+        ...MODEL_CONFIG,
+        device: 'webgpu'
+      });
+    }
+    if (!tokenizerInstance) {
+      tokenizerInstance = await AutoTokenizer.from_pretrained(MODEL_ID);
+    }
+  };
+  
   //const MODEL_ID = "openai/clip-vit-base-patch32";
   
   //type ProcessorType = Awaited<ReturnType<typeof AutoProcessor.from_pretrained>>;
@@ -143,10 +162,54 @@ export const initializeModels = async () => {
     return result[0];
   };
   
+  export const getTextEmbeddings = async (text: string): Promise<number[]> => {
+    if (!navigator.gpu) {
+        throw new Error("WebGPU not supported in this browser.");
+    }
+
+    await initializeTextModels();
+    if (!textModelInstance || !tokenizerInstance) {
+        throw new Error("Failed to initialize text models");
+    }
+
+    const inputs = tokenizerInstance([text], { padding: true, truncation: true });
+    const { text_embeds } = await textModelInstance(inputs);
+    const normalizedEmbeds = text_embeds.normalize();
+    const result = normalizedEmbeds.tolist();
+
+    text_embeds.dispose();
+    normalizedEmbeds.dispose();
+
+    console.log("[@UI/embeddings] Text embedding dimensions:", result[0].length);
+    return result[0];
+  };
+
+  // Mirrors protocol::WorkInput as serialized by the provider
+  export type WorkInput =
+    | { ImageUri: { uri: string } }
+    | { ImageBytes: { mime: string; bytes: number[] } }
+    | { Text: { text: string } };
+
+  export const getInputEmbeddings = async (input: WorkInput): Promise<number[]> => {
+    if ('Text' in input) {
+      return getTextEmbeddings(input.Text.text);
+    }
+    if ('ImageBytes' in input) {
+      const blob = new Blob([new Uint8Array(input.ImageBytes.bytes)], { type: input.ImageBytes.mime });
+      return getImageEmbeddings(blob);
+    }
+    return getImageEmbeddings(input.ImageUri.uri);
+  };
+  
   export const disposeModels = async () => {
     if (visionModelInstance) {
       await visionModelInstance.dispose();
       visionModelInstance = null;
     }
+    if (textModelInstance) {
+      await textModelInstance.dispose();
+      textModelInstance = null;
+    }
     processorInstance = null;
+    tokenizerInstance = null;
   };
\ No newline at end of file